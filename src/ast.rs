@@ -3,10 +3,12 @@
 // This is a 1:1 representation of Lustre source files.
 //
 // Dot operators can be applied to floats (non-dot operators can be applied to integers).
+//
+// Expressions, equations and declarations keep the span of source code they are parsed from.
 
-use std::collections::HashMap;
+pub use crate::diagnostic::Span;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Type {
 	Unit,
 	Bool,
@@ -14,15 +16,27 @@ pub enum Type {
 	Float,
 	String,
 	Tuple(Vec<Type>),
+	/// Array with a fixed number of items (`int^8`)
+	Array(Box<Type>, usize),
+	/// Record declared at the top-level of the file
+	Record(String),
+	/// Enumeration declared at the top-level of the file
+	Enum(String),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Const {
 	Unit,
 	Bool(bool),
 	Int(i32),
 	Float(f32),
 	String(String),
+	/// Array of constants, only built by the normalizer for `fby` initial values
+	Array(Vec<Const>),
+	/// Record of constants, only built by the normalizer for `fby` initial values
+	Record(String, Vec<(String, Const)>),
+	/// Value of an enumeration, with the name of the enumeration
+	Enum(String, String),
 }
 
 /// Unary operators.
@@ -33,6 +47,18 @@ pub enum Unop {
 	Not,
 }
 
+/// Static array iterators, which call a node on each item of their array arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Iter {
+	/// Calls the node on each item and yields arrays of results
+	Map,
+	/// Threads an accumulator through the calls, and yields it along with arrays of the other
+	/// results
+	Fold,
+	/// Threads an accumulator through the calls and only yields the accumulator
+	Red,
+}
+
 /// Binary operators.
 #[derive(Debug, Clone, Copy)]
 pub enum Binop {
@@ -49,38 +75,180 @@ pub enum Binop {
 	Leq,
 	Geq,
 	Eq,
+	Neq,
 	And,
 	Or,
+	Xor,
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+	pub kind: ExprKind,
+	pub span: Span,
 }
 
 #[derive(Debug, Clone)]
-pub enum Expr {
+pub enum ExprKind {
+	/// Calls a node, whose memory is reset when the condition is true (`reset f(x) every c`)
 	Call{
 		name: String,
 		args: Vec<Expr>,
+		reset: Option<Box<Expr>>,
+	},
+	/// Applies a node with an iterator (`map<<f, 4>>(a)`)
+	Iterate{
+		iter: Iter,
+		name: String,
+		size: usize,
+		args: Vec<Expr>,
 	},
 	Const(Const),
 	Unop(Unop, Box<Expr>),
 	Binop(Binop, Box<(Expr, Expr)>),
 	/// Yields an initial value followed by an expression
 	Fby(Box<(Expr, Expr)>),
+	/// Yields the previous value of an expression (undefined at the first instant)
+	Pre(Box<Expr>),
+	/// Yields the first expression at the first instant, then the second one
+	Arrow(Box<(Expr, Expr)>),
 	If(Box<(Expr, Expr, Expr)>),
+	/// Samples an expression when a clock variable is equal to a value (`e when c`, `e when not c`)
+	When(Box<Expr>, String, Const),
+	/// Combines expressions sampled on each value of a clock variable, which is either a boolean
+	/// or an enumeration
+	Merge(String, Vec<(Const, Expr)>),
+	/// Holds the last value of a sampled expression on the clock of its clock variable
+	Current(Box<Expr>),
 	/// Reference to the result of another equation.
 	Ident(String),
+	/// Reference to a constant declared at the top-level of the file
+	Global(String),
 	Tuple(Vec<Expr>),
+	/// Array literal (`[a, b, c]`)
+	Array(Vec<Expr>),
+	/// Array containing an item repeated a number of times (`x^4`)
+	Repeat(Box<Expr>, usize),
+	/// Array item (`a[i]`)
+	Index(Box<(Expr, Expr)>),
+	/// Sub-array between two static indices, both included (`a[2..5]`)
+	Slice(Box<Expr>, usize, usize),
+	/// Record construction (`point { x = 1.0; y = 2.0 }`)
+	Record(String, Vec<(String, Expr)>),
+	/// Record field (`p.x`)
+	Field(Box<Expr>, String),
 }
 
 #[derive(Debug, Clone)]
 pub struct Equation {
 	pub names: Vec<String>,
 	pub body: Expr,
+	pub span: Span,
+}
+
+/// Transition of an automaton, taken when its condition is true.
+#[derive(Debug, Clone)]
+pub struct Transition {
+	pub cond: Expr,
+	/// Whether the target state is restarted (`then`) or resumed (`continue`)
+	pub restart: bool,
+	pub target: String,
+	pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct State {
+	pub name: String,
+	pub locals: Vec<(String, Type)>,
+	/// Strong transitions, checked before the body is computed
+	pub unless: Vec<Transition>,
+	pub body: Vec<Equation>,
+	pub automata: Vec<Automaton>,
+	/// Weak transitions, checked after the body is computed
+	pub until: Vec<Transition>,
+	pub span: Span,
+}
+
+/// Automaton in the body of a node or of a state. Its first state is the initial one.
+#[derive(Debug, Clone)]
+pub struct Automaton {
+	pub states: Vec<State>,
+	pub span: Span,
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
 	pub name: String,
-	pub args_in: HashMap<String, Type>,
-	pub args_out: HashMap<String, Type>,
-	pub locals: HashMap<String, Type>,
+	pub args_in: Vec<(String, Type)>,
+	pub args_out: Vec<(String, Type)>,
+	pub locals: Vec<(String, Type)>,
 	pub body: Vec<Equation>,
+	/// Automata of the body, which are lowered to equations before type checking
+	pub automata: Vec<Automaton>,
+	pub span: Span,
+}
+
+/// Node with static parameters. It is not type-checked nor compiled by itself: it is parsed again
+/// for each list of static arguments it is called with, which gives a regular node.
+#[derive(Debug, Clone)]
+pub struct GenericNode {
+	pub name: String,
+	/// Name and type of each static parameter
+	pub statics: Vec<(String, Type)>,
+	/// Source code of the node
+	pub span: Span,
+	/// Node parsed with placeholder static arguments, whose definitions are checked even if the
+	/// generic node is never called
+	pub body: Option<Node>,
+}
+
+/// Definition of a type declared at the top-level of a file.
+#[derive(Debug, Clone)]
+pub enum TypeDef {
+	/// Record with named fields
+	Record(Vec<(String, Type)>),
+	/// Enumeration of named values
+	Enum(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TypeDecl {
+	pub name: String,
+	pub def: TypeDef,
+	pub span: Span,
+}
+
+/// Kind of an external declaration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExternKind {
+	Function,
+	/// Stateful node, which takes a mutable reference to its memory
+	Node,
+}
+
+/// Function or node declared in Lustre, but implemented in Rust.
+#[derive(Debug, Clone)]
+pub struct ExternDecl {
+	pub kind: ExternKind,
+	pub name: String,
+	pub args_in: Vec<(String, Type)>,
+	pub args_out: Vec<(String, Type)>,
+	pub span: Span,
+}
+
+/// Constant declared at the top-level of a file, whose value is evaluated at compile-time.
+#[derive(Debug, Clone)]
+pub struct ConstDecl {
+	pub name: String,
+	pub typ: Type,
+	pub value: Expr,
+	pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+	pub types: Vec<TypeDecl>,
+	pub consts: Vec<ConstDecl>,
+	pub externs: Vec<ExternDecl>,
+	pub generics: Vec<GenericNode>,
+	pub nodes: Vec<Node>,
 }
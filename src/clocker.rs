@@ -0,0 +1,331 @@
+// Clock calculus infers the clock of each equation of a normalized AST and checks that expressions
+// only combine streams which are present at the same instants.
+//
+// The inputs and outputs of a node are on its base clock. The other clocks are sub-clocks, built
+// with the `when` operator:
+//
+// - `e when c` is on a sub-clock of the clock of `c`, which is only active when `c` is true (or
+//   false for `e when not c`)
+// - `e when A(c)` is on a sub-clock of the clock of `c`, which is only active when the enumeration
+//   `c` is equal to `A`
+// - `merge c (e1) (e2)` combines `e1` on `c` and `e2` on `not c` into a stream on the clock of `c`,
+//   and `merge c (A -> e1) (B -> e2)` combines `e1` on `A(c)` and `e2` on `B(c)`
+// - `current e` holds the last value of `e` on the clock of the clock variable of `e`
+//
+// Constants can be used on any clock. The operands of the other operators and the arguments of a
+// call must all be on the same clock. The result of a call is on the clock of its arguments: the
+// callee is only stepped when this clock is active.
+//
+// Clocks are inferred by unification: each local variable and constant starts with an unknown
+// clock, which is refined by the constraints of the expressions it appears in. Clocks which are
+// still unknown at the end are set to the base clock.
+
+use std::collections::HashMap;
+use crate::nast::*;
+use crate::diagnostic::{CompileError, collect, error};
+
+/// A clock which can contain unknowns.
+#[derive(Debug, Clone)]
+enum Ck {
+	Base,
+	On(Box<Ck>, String, Const),
+	Var(usize),
+}
+
+struct Context {
+	/// Span of the equation being clocked, used in error messages since basic expressions have no
+	/// span (see `nast.rs`)
+	span: Span,
+	/// Clock of each variable of the node
+	vars: HashMap<String, Ck>,
+	/// Value of each unknown, if any
+	subst: Vec<Option<Ck>>,
+}
+
+impl Context {
+	fn new(node: &Node) -> Self {
+		let mut ctx = Context{
+			span: node.span.clone(),
+			vars: HashMap::new(),
+			subst: Vec::new(),
+		};
+		for (name, _) in node.args_in.iter().chain(node.args_out.iter()) {
+			ctx.vars.insert(name.clone(), Ck::Base);
+		}
+		for (name, _) in &node.locals {
+			let ck = ctx.fresh();
+			ctx.vars.insert(name.clone(), ck);
+		}
+		ctx
+	}
+
+	fn error<T>(&self, msg: String) -> Result<T, CompileError> {
+		error(CompileError::Clock, &self.span, msg)
+	}
+
+	fn fresh(&mut self) -> Ck {
+		self.subst.push(None);
+		Ck::Var(self.subst.len() - 1)
+	}
+
+	fn var(&self, name: &str) -> Result<Ck, CompileError> {
+		match self.vars.get(name) {
+			Some(ck) => Ok(ck.clone()),
+			None => error(CompileError::UnknownName, &self.span, format!("unknown variable `{}`", name)),
+		}
+	}
+
+	/// Follows known unknowns until the head of the clock is known.
+	fn shallow(&self, ck: &Ck) -> Ck {
+		if let Ck::Var(i) = ck {
+			if let Some(ck) = &self.subst[*i] {
+				return self.shallow(ck);
+			}
+		}
+		ck.clone()
+	}
+
+	fn occurs(&self, i: usize, ck: &Ck) -> bool {
+		match self.shallow(ck) {
+			Ck::Base => false,
+			Ck::On(parent, _, _) => self.occurs(i, &parent),
+			Ck::Var(j) => i == j,
+		}
+	}
+
+	fn unify(&mut self, a: &Ck, b: &Ck) -> bool {
+		match (self.shallow(a), self.shallow(b)) {
+			(Ck::Var(i), Ck::Var(j)) if i == j => true,
+			(Ck::Var(i), ck) | (ck, Ck::Var(i)) => {
+				if self.occurs(i, &ck) {
+					return false;
+				}
+				self.subst[i] = Some(ck);
+				true
+			},
+			(Ck::Base, Ck::Base) => true,
+			(Ck::On(p1, c1, v1), Ck::On(p2, c2, v2)) => {
+				c1 == c2 && v1 == v2 && self.unify(&p1, &p2)
+			},
+			_ => false,
+		}
+	}
+
+	fn unify_or_fail(&mut self, a: &Ck, b: &Ck, what: &str) -> Result<(), CompileError> {
+		if !self.unify(a, b) {
+			let (a, b) = (self.resolve(a), self.resolve(b));
+			return self.error(format!("{} are on different clocks ({} and {})", what, format_clock(&a), format_clock(&b)));
+		}
+		Ok(())
+	}
+
+	/// Replaces all known unknowns. The remaining ones are set to the base clock.
+	fn resolve(&self, ck: &Ck) -> Clock {
+		match self.shallow(ck) {
+			Ck::Base | Ck::Var(_) => Clock::Base,
+			Ck::On(parent, clock, value) => Clock::On(Box::new(self.resolve(&parent)), clock, value),
+		}
+	}
+
+	fn clock_of_atom(&mut self, a: &Atom) -> Result<Ck, CompileError> {
+		match a {
+			Atom::Ident(name) => self.var(name),
+			Atom::Const(_) | Atom::Global(_) => Ok(self.fresh()),
+		}
+	}
+
+	fn clock_of_bexprs<'b, I: Iterator<Item = &'b Bexpr>>(&mut self, exprs: I, what: &str) -> Result<Ck, CompileError> {
+		let ck = self.fresh();
+		for e in exprs {
+			let e_ck = self.clock_of_bexpr(e)?;
+			self.unify_or_fail(&ck, &e_ck, what)?;
+		}
+		Ok(ck)
+	}
+
+	fn clock_of_bexpr(&mut self, e: &Bexpr) -> Result<Ck, CompileError> {
+		Ok(match e {
+			Bexpr::Atom(a) => self.clock_of_atom(a)?,
+			Bexpr::Unop(_, e) => self.clock_of_bexpr(e)?,
+			Bexpr::Binop(_, exprs) => {
+				let (e1, e2): &(Bexpr, Bexpr) = exprs;
+				self.clock_of_bexprs(vec![e1, e2].into_iter(), "operands of a binary operator")?
+			},
+			Bexpr::If(iff) => {
+				let (cond, body, else_part): &(Bexpr, Bexpr, Bexpr) = iff;
+				self.clock_of_bexprs(vec![cond, body, else_part].into_iter(), "operands of `if`")?
+			},
+			Bexpr::Tuple(exprs) => self.clock_of_bexprs(exprs.iter(), "items of a tuple")?,
+			Bexpr::When(e, clock, value) => {
+				let e_ck = self.clock_of_bexpr(e)?;
+				let clock_ck = self.var(clock)?;
+				self.unify_or_fail(&e_ck, &clock_ck, &format!("`when` operand and `{}`", clock))?;
+				Ck::On(Box::new(clock_ck), clock.clone(), value.clone())
+			},
+			Bexpr::Merge(clock, cases) => {
+				let clock_ck = self.var(clock)?;
+				for (value, e) in cases {
+					let e_ck = self.clock_of_bexpr(e)?;
+					let case_ck = Ck::On(Box::new(clock_ck.clone()), clock.clone(), value.clone());
+					self.unify_or_fail(&e_ck, &case_ck, &format!("`merge` case and `{}`", clock))?;
+				}
+				clock_ck
+			},
+			Bexpr::Array(exprs) => self.clock_of_bexprs(exprs.iter(), "items of an array")?,
+			Bexpr::Repeat(e, _) => self.clock_of_bexpr(e)?,
+			Bexpr::Index(a, i) => {
+				let a_ck = self.clock_of_atom(a)?;
+				let i_ck = self.clock_of_bexpr(i)?;
+				self.unify_or_fail(&a_ck, &i_ck, "array and index")?;
+				a_ck
+			},
+			Bexpr::Record(_, values) => {
+				self.clock_of_bexprs(values.iter().map(|(_, e)| e), "fields of a record")?
+			},
+			Bexpr::Field(e, _) => self.clock_of_bexpr(e)?,
+		})
+	}
+
+	fn clock_of_expr(&mut self, e: &Expr, currents: &mut Vec<(Ck, Ck)>) -> Result<Ck, CompileError> {
+		Ok(match e {
+			Expr::Bexpr(e) => self.clock_of_bexpr(e)?,
+			Expr::Call{name, args, reset} => {
+				let ck = self.clock_of_bexprs(args.iter(), &format!("arguments of `{}`", name))?;
+				if let Some(cond) = reset {
+					let cond_ck = self.clock_of_atom(cond)?;
+					self.unify_or_fail(&ck, &cond_ck, &format!("arguments and reset condition of `{}`", name))?;
+				}
+				ck
+			},
+			Expr::Iterate{name, args, ..} => {
+				let ck = self.fresh();
+				for a in args {
+					let a_ck = self.clock_of_atom(a)?;
+					self.unify_or_fail(&ck, &a_ck, &format!("arguments of `{}`", name))?;
+				}
+				ck
+			},
+			Expr::Fby(init, next) => {
+				let ck = self.fresh();
+				for a in init {
+					let a_ck = self.clock_of_atom(a)?;
+					self.unify_or_fail(&ck, &a_ck, "operands of `fby`")?;
+				}
+				let next_ck = self.clock_of_bexprs(next.iter(), "operands of `fby`")?;
+				self.unify_or_fail(&ck, &next_ck, "operands of `fby`")?;
+				ck
+			},
+			Expr::Pre(e) => self.clock_of_bexprs(e.iter(), "items of `pre`")?,
+			Expr::Arrow(first, next) => {
+				self.clock_of_bexprs(first.iter().chain(next.iter()), "operands of `->`")?
+			},
+			Expr::Current(a) => {
+				// The clock of the atom may not be known yet
+				let ck = self.fresh();
+				let a_ck = self.clock_of_atom(a)?;
+				currents.push((ck.clone(), a_ck));
+				ck
+			},
+			// Calls are only split by the scheduler
+			Expr::Outputs{..} | Expr::Step{..} => unreachable!(),
+		})
+	}
+
+	/// Solves `current` constraints: the result is on the parent clock of the operand.
+	fn solve_currents(&mut self, mut currents: Vec<(Ck, Ck)>) -> Result<(), CompileError> {
+		while !currents.is_empty() {
+			let mut remaining = Vec::new();
+			for (ck, a_ck) in currents.iter() {
+				match self.shallow(a_ck) {
+					Ck::On(parent, _, _) => self.unify_or_fail(ck, &parent, "`current` and its operand")?,
+					Ck::Base => return self.error("operand of `current` is on the base clock".to_string()),
+					Ck::Var(_) => remaining.push((ck.clone(), a_ck.clone())),
+				}
+			}
+			if remaining.len() == currents.len() {
+				return self.error("cannot infer the clock of the operand of `current`".to_string());
+			}
+			currents = remaining;
+		}
+		Ok(())
+	}
+}
+
+fn format_clock(c: &Clock) -> String {
+	match c {
+		Clock::Base => "base".to_string(),
+		Clock::On(parent, clock, Const::Bool(false)) => {
+			format!("{} on not {}", format_clock(parent), clock)
+		},
+		Clock::On(parent, clock, Const::Enum(_, variant)) => {
+			format!("{} on {}({})", format_clock(parent), variant, clock)
+		},
+		Clock::On(parent, clock, _) => format!("{} on {}", format_clock(parent), clock),
+	}
+}
+
+/// Infers the clock of each equation of a node. Each equation is checked even if the previous ones
+/// have errors.
+fn clock_node(n: &Node) -> Result<Node, CompileError> {
+	let mut ctx = Context::new(n);
+
+	let mut errors = Vec::new();
+	let mut currents = Vec::new();
+	let mut eq_clocks = Vec::new();
+	for eq in &n.body {
+		ctx.span = eq.span.clone();
+		let ck = ctx.clock_of_expr(&eq.body, &mut currents).and_then(|ck| {
+			for name in &eq.names {
+				let name_ck = ctx.var(name)?;
+				ctx.unify_or_fail(&name_ck, &ck, &format!("`{}` and its definition", name))?;
+			}
+			Ok(ck)
+		});
+		// The equations reading the variables of an equation with errors see them on an unknown clock
+		eq_clocks.push(ck.unwrap_or_else(|err| {
+			errors.push(err);
+			ctx.fresh()
+		}));
+	}
+	ctx.span = n.span.clone();
+	errors.extend(ctx.solve_currents(currents).err());
+	collect(errors)?;
+
+	let body = n.body.iter().zip(eq_clocks.iter()).map(|(eq, ck)| {
+		Equation{
+			names: eq.names.clone(),
+			body: eq.body.clone(),
+			clock: ctx.resolve(ck),
+			span: eq.span.clone(),
+			intermediate: eq.intermediate,
+		}
+	}).collect();
+
+	Ok(Node{
+		name: n.name.clone(),
+		args_in: n.args_in.clone(),
+		args_out: n.args_out.clone(),
+		locals: n.locals.clone(),
+		body,
+		groups: n.groups.clone(),
+		span: n.span.clone(),
+	})
+}
+
+/// Infers the clocks of each node. Errors are added to `errors`, and the nodes with errors are left
+/// out.
+pub fn clock(f: &File, errors: &mut Vec<CompileError>) -> File {
+	let mut nodes = Vec::new();
+	for n in &f.nodes {
+		match clock_node(n) {
+			Ok(n) => nodes.push(n),
+			Err(err) => errors.push(err),
+		}
+	}
+	File{
+		types: f.types.clone(),
+		consts: f.consts.clone(),
+		externs: f.externs.clone(),
+		nodes,
+	}
+}
@@ -0,0 +1,201 @@
+// Definition checks make sure that each variable of a node is declared, defined and used:
+//
+// - Each identifier, including the clock variables of `when` and `merge`, refers to an input, an
+//   output or a local variable of the node, or to a local variable of an enclosing state
+// - Each output and local variable is defined by exactly one equation or automaton, and inputs are
+//   not defined
+// - Each local variable of a state is defined exactly once in this state
+//
+// An automaton defines the variables defined by its states, except their local variables.
+//
+// Inputs and local variables which are never read are reported as warnings, except inputs of type
+// `unit`, which are used by nodes that don't need any input.
+//
+// This is done on the raw AST before lowering, so that the variables generated for automata are
+// not reported. Afterwards, the dependencies of each equation are known to be defined, which the
+// sequentializer relies on.
+
+use std::collections::HashSet;
+use crate::ast::*;
+use crate::diagnostic::{CompileError, Diagnostic, collect};
+
+struct Checker<'a> {
+	node: &'a Node,
+	/// Variables of the node and local variables of the enclosing states
+	visible: Vec<String>,
+	/// Variables read so far
+	read: HashSet<String>,
+	errors: Vec<CompileError>,
+	warnings: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+	fn read(&mut self, name: &str, span: &Span) {
+		if !self.visible.iter().any(|v| v == name) {
+			let msg = format!("unknown variable `{}` in node `{}`", name, &self.node.name);
+			self.errors.push(CompileError::UnknownName(Diagnostic::new(span, msg)));
+		}
+		self.read.insert(name.to_string());
+	}
+
+	fn check_expr(&mut self, e: &Expr) {
+		match &e.kind {
+			ExprKind::Ident(name) => self.read(name, &e.span),
+			ExprKind::Const(_) | ExprKind::Global(_) => {},
+			ExprKind::When(inner, clock, _) => {
+				self.read(clock, &e.span);
+				self.check_expr(inner);
+			},
+			ExprKind::Merge(clock, cases) => {
+				self.read(clock, &e.span);
+				for (_, e) in cases {
+					self.check_expr(e);
+				}
+			},
+			ExprKind::Call{args, reset, ..} => {
+				for e in args.iter().chain(reset.iter().map(|e| &**e)) {
+					self.check_expr(e);
+				}
+			},
+			ExprKind::Iterate{args: exprs, ..} | ExprKind::Tuple(exprs) | ExprKind::Array(exprs) => {
+				for e in exprs {
+					self.check_expr(e);
+				}
+			},
+			ExprKind::Unop(_, e) | ExprKind::Pre(e) | ExprKind::Current(e) | ExprKind::Repeat(e, _) |
+			ExprKind::Slice(e, _, _) | ExprKind::Field(e, _) => self.check_expr(e),
+			ExprKind::Binop(_, exprs) | ExprKind::Fby(exprs) | ExprKind::Arrow(exprs) | ExprKind::Index(exprs) => {
+				let (e1, e2): &(Expr, Expr) = exprs;
+				self.check_expr(e1);
+				self.check_expr(e2);
+			},
+			ExprKind::If(iff) => {
+				let (cond, body, else_part): &(Expr, Expr, Expr) = iff;
+				self.check_expr(cond);
+				self.check_expr(body);
+				self.check_expr(else_part);
+			},
+			ExprKind::Record(_, values) => {
+				for (_, e) in values {
+					self.check_expr(e);
+				}
+			},
+		}
+	}
+
+	/// Checks equations and automata, and returns the variables they define along with the span
+	/// of each definition.
+	fn check_block(&mut self, body: &[Equation], automata: &[Automaton]) -> Vec<(String, Span)> {
+		let mut defined = Vec::new();
+		for eq in body {
+			self.check_expr(&eq.body);
+			for name in &eq.names {
+				if !self.visible.contains(name) {
+					let msg = format!("unknown variable `{}` in node `{}`", name, &self.node.name);
+					self.errors.push(CompileError::UnknownName(Diagnostic::new(&eq.span, msg)));
+				} else if self.node.args_in.iter().any(|(input, _)| input == name) {
+					let msg = format!("input `{}` of node `{}` cannot be defined", name, &self.node.name);
+					self.errors.push(CompileError::Definition(Diagnostic::new(&eq.span, msg)));
+				}
+				defined.push((name.clone(), eq.span.clone()));
+			}
+		}
+		for a in automata {
+			let mut names: Vec<String> = Vec::new();
+			for s in &a.states {
+				for name in self.check_state(s) {
+					if !names.contains(&name) {
+						names.push(name);
+					}
+				}
+			}
+			defined.extend(names.into_iter().map(|name| (name, a.span.clone())));
+		}
+		defined
+	}
+
+	/// Checks a state, and returns the variables it defines, except its local variables.
+	fn check_state(&mut self, s: &State) -> Vec<String> {
+		// Local variables of the state hide the ones of the enclosing states
+		let n_visible = self.visible.len();
+		let hidden: Vec<&String> = s.locals.iter().map(|(name, _)| name).filter(|name| self.read.remove(*name)).collect();
+		self.visible.extend(s.locals.iter().map(|(name, _)| name.clone()));
+
+		for t in s.unless.iter().chain(s.until.iter()) {
+			self.check_expr(&t.cond);
+		}
+		let defined = self.check_block(&s.body, &s.automata);
+		for (name, _) in &s.locals {
+			let what = format!("local variable `{}` of state `{}`", name, &s.name);
+			self.check_defined(name, &what, &defined, &s.span);
+			self.check_read(name, &what, &s.span);
+		}
+
+		self.visible.truncate(n_visible);
+		for (name, _) in &s.locals {
+			self.read.remove(name);
+		}
+		self.read.extend(hidden.into_iter().cloned());
+		defined.into_iter().map(|(name, _)| name).filter(|name| !s.locals.iter().any(|(local, _)| local == name)).collect()
+	}
+
+	/// Checks that a variable has exactly one definition. `span` is the span of its scope.
+	fn check_defined(&mut self, name: &str, what: &str, defined: &[(String, Span)], span: &Span) {
+		let mut defs = defined.iter().filter(|(other, _)| other == name);
+		match (defs.next(), defs.next()) {
+			(None, _) => {
+				let msg = format!("{} is not defined", what);
+				self.errors.push(CompileError::Definition(Diagnostic::new(span, msg)));
+			},
+			(Some(_), Some((_, span))) => {
+				let msg = format!("{} is defined twice", what);
+				self.errors.push(CompileError::Definition(Diagnostic::new(span, msg)));
+			},
+			(Some(_), None) => {},
+		}
+	}
+
+	fn check_read(&mut self, name: &str, what: &str, span: &Span) {
+		if !self.read.contains(name) {
+			self.warnings.push(Diagnostic::warning(span, format!("{} is never used", what)));
+		}
+	}
+}
+
+fn check_node(n: &Node, errors: &mut Vec<CompileError>, warnings: &mut Vec<Diagnostic>) {
+	let vars = n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter());
+	let mut checker = Checker{
+		node: n,
+		visible: vars.map(|(name, _)| name.clone()).collect(),
+		read: HashSet::new(),
+		errors: Vec::new(),
+		warnings: Vec::new(),
+	};
+	let defined = checker.check_block(&n.body, &n.automata);
+	for (name, _) in &n.args_out {
+		checker.check_defined(name, &format!("output `{}` of node `{}`", name, &n.name), &defined, &n.span);
+	}
+	for (name, _) in &n.locals {
+		let what = format!("local variable `{}` of node `{}`", name, &n.name);
+		checker.check_defined(name, &what, &defined, &n.span);
+		checker.check_read(name, &what, &n.span);
+	}
+	for (name, _) in n.args_in.iter().filter(|(_, t)| *t != Type::Unit) {
+		checker.check_read(name, &format!("input `{}` of node `{}`", name, &n.name), &n.span);
+	}
+	errors.append(&mut checker.errors);
+	warnings.append(&mut checker.warnings);
+}
+
+/// Checks the definitions of the variables of each node. Warnings are added to `warnings`, sorted
+/// by position.
+pub fn check_definitions(f: &File, warnings: &mut Vec<Diagnostic>) -> Result<(), CompileError> {
+	let mut errors = Vec::new();
+	// Instances, whose names start with `_`, are checked once through the body of their generic node
+	let generics = f.generics.iter().filter_map(|g| g.body.as_ref());
+	for n in f.nodes.iter().filter(|n| !n.name.starts_with('_')).chain(generics) {
+		check_node(n, &mut errors, warnings);
+	}
+	warnings.sort_by_key(|w| (w.span.source.name.clone(), w.span.start));
+	collect(errors)
+}
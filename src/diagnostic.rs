@@ -0,0 +1,244 @@
+// Diagnostics
+//
+// The parser records the span of source code each item of the raw AST comes from. Spans are kept
+// by the following steps: expressions created by the lowering and the normalization get the span
+// of the source code they stand for, and the equations and nodes of the normalized AST keep the
+// span of the raw ones.
+//
+// Errors stop the compilation: each step returns a `CompileError`, which tells the kind of error.
+// The semantic steps keep going after an error, and the following steps keep going with the nodes
+// without errors (see `compile` in `main.rs`), so that all the errors of a file are reported at
+// once, sorted by position. Warnings are reported too, but don't stop the compilation.
+//
+// Diagnostics are reported with the location of their span, followed by the line of source code
+// where it starts, with the span underlined:
+//
+// ```
+// error: unknown variable `y`
+//  --> test/simple.mls:4:7
+//   |
+// 4 |   o = y + 1;
+//   |       ^
+// ```
+
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+/// Source code of a file.
+pub struct Source {
+	pub name: String,
+	pub text: String,
+	/// Offset of the start of each line
+	lines: Vec<usize>,
+}
+
+impl Source {
+	pub fn new(name: String, text: String) -> Self {
+		let lines = std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect();
+		Source{name, text, lines}
+	}
+}
+
+/// Range of bytes of a source file.
+#[derive(Clone)]
+pub struct Span {
+	pub source: Rc<Source>,
+	pub start: usize,
+	pub end: usize,
+}
+
+impl Span {
+	pub fn new(source: &Rc<Source>, start: usize, end: usize) -> Self {
+		Span{source: source.clone(), start, end}
+	}
+
+	/// Returns the span starting at the beginning of this one and ending at the end of `other`.
+	pub fn to(&self, other: &Span) -> Span {
+		Span::new(&self.source, self.start, other.end.max(self.end))
+	}
+
+	/// Returns whether the span is inside `other`.
+	pub fn is_in(&self, other: &Span) -> bool {
+		self.source.name == other.source.name && other.start <= self.start && self.end <= other.end
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.source.text[self.start..self.end]
+	}
+
+	/// Returns the line and column where the span starts, starting from 1.
+	pub fn line_col(&self) -> (usize, usize) {
+		let lines = &self.source.lines;
+		let line = lines.partition_point(|&start| start <= self.start);
+		(line, self.source.text[lines[line - 1]..self.start].chars().count() + 1)
+	}
+}
+
+// Spans are printed as their location, to keep dumps of the AST readable
+impl fmt::Debug for Span {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let (line, col) = self.line_col();
+		write!(f, "{}:{}:{}", &self.source.name, line, col)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+	Error,
+	/// Suspicious code, which doesn't stop the compilation
+	Warning,
+}
+
+/// Message about a span of source code.
+#[derive(Debug)]
+pub struct Diagnostic {
+	pub level: Level,
+	pub span: Span,
+	pub message: String,
+	/// Additional explanations, printed after the source code (`note: ...`, `help: ...`)
+	pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+	pub fn new(span: &Span, message: String) -> Self {
+		Diagnostic{level: Level::Error, span: span.clone(), message, notes: Vec::new()}
+	}
+
+	pub fn warning(span: &Span, message: String) -> Self {
+		Diagnostic{level: Level::Warning, span: span.clone(), message, notes: Vec::new()}
+	}
+
+	pub fn with_note(mut self, note: String) -> Self {
+		self.notes.push(note);
+		self
+	}
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let text = &self.span.source.text;
+		let (line, col) = self.span.line_col();
+		let line_start = text[..self.span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+		let line_end = text[line_start..].find('\n').map(|i| line_start + i).unwrap_or_else(|| text.len());
+		let source_line = &text[line_start..line_end];
+
+		// Keep tabs in the indentation of the underline, so that it lines up with the source
+		let indent: String = text[line_start..self.span.start].chars().map(|c| {
+			if c == '\t' { '\t' } else { ' ' }
+		}).collect();
+		let end = self.span.end.min(line_end).max(self.span.start);
+		let width = text[self.span.start..end].chars().count().max(1);
+
+		let gutter = " ".repeat(line.to_string().len());
+		let level = match self.level {
+			Level::Error => "error",
+			Level::Warning => "warning",
+		};
+		writeln!(f, "{}: {}", level, &self.message)?;
+		writeln!(f, "{}--> {}:{}:{}", &gutter, &self.span.source.name, line, col)?;
+		writeln!(f, "{} |", &gutter)?;
+		writeln!(f, "{} | {}", line, source_line)?;
+		write!(f, "{} | {}{}", &gutter, indent, "^".repeat(width))?;
+		for note in &self.notes {
+			write!(f, "\n{} = {}", &gutter, note)?;
+		}
+		Ok(())
+	}
+}
+
+/// Error which stops the compilation.
+#[derive(Debug)]
+pub enum CompileError {
+	/// Invalid command-line arguments
+	Usage(String),
+	/// File which cannot be read or loaded
+	Load(String),
+	/// Invalid syntax
+	Parse(Diagnostic),
+	/// Reference to an undeclared variable, node, type, constant or state
+	UnknownName(Diagnostic),
+	/// Item defined twice or recursively, or not defined where it should be
+	Definition(Diagnostic),
+	/// Wrong number of arguments, static arguments or tuple items
+	Arity(Diagnostic),
+	/// Expression of the wrong type, or constant expression which cannot be evaluated
+	Type(Diagnostic),
+	/// Expressions combining streams on different clocks
+	Clock(Diagnostic),
+	/// Equations which depend on each other in the same instant
+	Causality(Diagnostic),
+	/// Failure to write the generated code
+	Io(io::Error),
+	/// Several errors, sorted by position
+	Multiple(Vec<CompileError>),
+}
+
+impl CompileError {
+	/// Returns the span the error is about, if any.
+	pub fn span(&self) -> Option<&Span> {
+		match self {
+			CompileError::Parse(d) | CompileError::UnknownName(d) | CompileError::Definition(d) |
+			CompileError::Arity(d) | CompileError::Type(d) | CompileError::Clock(d) |
+			CompileError::Causality(d) => Some(&d.span),
+			CompileError::Usage(_) | CompileError::Load(_) | CompileError::Io(_) | CompileError::Multiple(_) => None,
+		}
+	}
+
+	/// Returns whether the error, or one of several errors, is about the source code in `span`.
+	pub fn is_in(&self, span: &Span) -> bool {
+		match self {
+			CompileError::Multiple(errors) => errors.iter().any(|err| err.is_in(span)),
+			err => err.span().is_some_and(|err_span| err_span.is_in(span)),
+		}
+	}
+}
+
+impl fmt::Display for CompileError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			CompileError::Usage(msg) => write!(f, "error: {}\nusage: rustre [--inline[=NODE,...]] [FILE]", msg),
+			CompileError::Load(msg) => write!(f, "error: {}", msg),
+			CompileError::Parse(d) | CompileError::UnknownName(d) | CompileError::Definition(d) |
+			CompileError::Arity(d) | CompileError::Type(d) | CompileError::Clock(d) |
+			CompileError::Causality(d) => d.fmt(f),
+			CompileError::Io(err) => write!(f, "error: cannot write the generated code: {}", err),
+			CompileError::Multiple(errors) => {
+				for err in errors {
+					write!(f, "{}\n\n", err)?;
+				}
+				write!(f, "error: aborting due to {} errors", errors.len())
+			},
+		}
+	}
+}
+
+impl From<io::Error> for CompileError {
+	fn from(err: io::Error) -> Self {
+		CompileError::Io(err)
+	}
+}
+
+/// Returns an error of the given kind about a span of source code.
+pub fn error<T>(kind: fn(Diagnostic) -> CompileError, span: &Span, message: String) -> Result<T, CompileError> {
+	Err(kind(Diagnostic::new(span, message)))
+}
+
+/// Returns the errors found by a step, if any. Errors without a span come first, the other ones are
+/// sorted by position. Errors found by several steps are only reported once.
+pub fn collect(errors: Vec<CompileError>) -> Result<(), CompileError> {
+	let mut all = Vec::new();
+	for err in errors {
+		match err {
+			CompileError::Multiple(errors) => all.extend(errors),
+			err => all.push(err),
+		}
+	}
+	all.sort_by_key(|err| err.span().map(|span| (span.source.name.clone(), span.start)));
+	all.dedup_by(|a, b| a.to_string() == b.to_string());
+	match all.len() {
+		0 => Ok(()),
+		1 => Err(all.pop().unwrap()),
+		_ => Err(CompileError::Multiple(all)),
+	}
+}
@@ -0,0 +1,155 @@
+// Constant evaluation
+//
+// Evaluates expressions at compile-time. Constant expressions are made of literals, references to
+// constants declared at the top-level of the file, and operators applied to constant expressions.
+// For instance:
+//
+// ```lustre
+// const dt: float = 0.001;
+// const rate: int = 1000;
+// const period: float = dt *. float_of_int(rate);
+// ```
+//
+// is rejected because of the call, but `period` can be defined as `dt *. 1000.0`.
+//
+// Integer arithmetic is checked: overflows and divisions by zero are reported as errors instead of
+// wrapping or crashing the compiler. Errors are returned as messages, so that callers can either
+// report them or fall back to computing the expression at run-time.
+
+use std::collections::HashMap;
+use crate::ast::*;
+use crate::diagnostic::{CompileError, error};
+
+pub fn eval_unop(op: Unop, c: &Const) -> Result<Const, String> {
+	match (op, c) {
+		(Unop::Minus, Const::Int(i)) => match i.checked_neg() {
+			Some(i) => Ok(Const::Int(i)),
+			None => Err(format!("integer overflow in -{}", i)),
+		},
+		(Unop::MinusDot, Const::Float(f)) => Ok(Const::Float(-f)),
+		(Unop::Not, Const::Bool(b)) => Ok(Const::Bool(!b)),
+		_ => Err(format!("invalid operand {:?}", c)),
+	}
+}
+
+fn eval_int_binop(op: Binop, i1: i32, i2: i32) -> Result<Const, String> {
+	let (res, sym) = match op {
+		Binop::Plus => (i1.checked_add(i2), "+"),
+		Binop::Minus => (i1.checked_sub(i2), "-"),
+		Binop::Mult => (i1.checked_mul(i2), "*"),
+		Binop::Div => {
+			if i2 == 0 {
+				return Err(format!("division by zero in {} / {}", i1, i2));
+			}
+			(i1.checked_div(i2), "/")
+		},
+		_ => unreachable!(),
+	};
+	match res {
+		Some(i) => Ok(Const::Int(i)),
+		None => Err(format!("integer overflow in {} {} {}", i1, sym, i2)),
+	}
+}
+
+pub fn eval_binop(op: Binop, c1: &Const, c2: &Const) -> Result<Const, String> {
+	match (op, c1, c2) {
+		(Binop::Plus, Const::Int(i1), Const::Int(i2)) |
+		(Binop::Minus, Const::Int(i1), Const::Int(i2)) |
+		(Binop::Mult, Const::Int(i1), Const::Int(i2)) |
+		(Binop::Div, Const::Int(i1), Const::Int(i2)) => eval_int_binop(op, *i1, *i2),
+		(Binop::PlusDot, Const::Float(f1), Const::Float(f2)) => Ok(Const::Float(f1 + f2)),
+		(Binop::MinusDot, Const::Float(f1), Const::Float(f2)) => Ok(Const::Float(f1 - f2)),
+		(Binop::MultDot, Const::Float(f1), Const::Float(f2)) => Ok(Const::Float(f1 * f2)),
+		(Binop::DivDot, Const::Float(f1), Const::Float(f2)) => Ok(Const::Float(f1 / f2)),
+		(Binop::Lt, Const::Int(i1), Const::Int(i2)) => Ok(Const::Bool(i1 < i2)),
+		(Binop::Gt, Const::Int(i1), Const::Int(i2)) => Ok(Const::Bool(i1 > i2)),
+		(Binop::Leq, Const::Int(i1), Const::Int(i2)) => Ok(Const::Bool(i1 <= i2)),
+		(Binop::Geq, Const::Int(i1), Const::Int(i2)) => Ok(Const::Bool(i1 >= i2)),
+		(Binop::Lt, Const::Float(f1), Const::Float(f2)) => Ok(Const::Bool(f1 < f2)),
+		(Binop::Gt, Const::Float(f1), Const::Float(f2)) => Ok(Const::Bool(f1 > f2)),
+		(Binop::Leq, Const::Float(f1), Const::Float(f2)) => Ok(Const::Bool(f1 <= f2)),
+		(Binop::Geq, Const::Float(f1), Const::Float(f2)) => Ok(Const::Bool(f1 >= f2)),
+		(Binop::Eq, _, _) => Ok(Const::Bool(c1 == c2)),
+		(Binop::Neq, _, _) => Ok(Const::Bool(c1 != c2)),
+		(Binop::And, Const::Bool(b1), Const::Bool(b2)) => Ok(Const::Bool(*b1 && *b2)),
+		(Binop::Or, Const::Bool(b1), Const::Bool(b2)) => Ok(Const::Bool(*b1 || *b2)),
+		(Binop::Xor, Const::Bool(b1), Const::Bool(b2)) => Ok(Const::Bool(b1 != b2)),
+		_ => Err(format!("invalid operands {:?} and {:?}", c1, c2)),
+	}
+}
+
+/// Evaluates a constant expression, given the values of the constants it can refer to.
+pub fn eval(e: &Expr, consts: &HashMap<String, Const>) -> Result<Const, String> {
+	match &e.kind {
+		ExprKind::Const(c) => Ok(c.clone()),
+		ExprKind::Global(name) => match consts.get(name) {
+			Some(c) => Ok(c.clone()),
+			None => Err(format!("unknown constant `{}`", name)),
+		},
+		ExprKind::Unop(op, e) => eval_unop(*op, &eval(e, consts)?),
+		ExprKind::Binop(op, exprs) => {
+			let (e1, e2): &(Expr, Expr) = exprs;
+			eval_binop(*op, &eval(e1, consts)?, &eval(e2, consts)?)
+		},
+		ExprKind::If(iff) => {
+			let (cond, body, else_part): &(Expr, Expr, Expr) = iff;
+			match eval(cond, consts)? {
+				Const::Bool(true) => eval(body, consts),
+				Const::Bool(false) => eval(else_part, consts),
+				c => Err(format!("invalid condition {:?}", c)),
+			}
+		},
+		ExprKind::Array(exprs) => {
+			let consts: Result<Vec<Const>, String> = exprs.iter().map(|e| eval(e, consts)).collect();
+			Ok(Const::Array(consts?))
+		},
+		ExprKind::Repeat(e, size) => Ok(Const::Array(vec![eval(e, consts)?; *size])),
+		ExprKind::Index(index) => {
+			let (e, i): &(Expr, Expr) = index;
+			match (eval(e, consts)?, eval(i, consts)?) {
+				(Const::Array(items), Const::Int(i)) => {
+					if i < 0 || i as usize >= items.len() {
+						return Err(format!("index {} is out of bounds for an array of size {}", i, items.len()));
+					}
+					Ok(items[i as usize].clone())
+				},
+				(c, i) => Err(format!("invalid index {:?} of {:?}", i, c)),
+			}
+		},
+		ExprKind::Slice(e, start, end) => match eval(e, consts)? {
+			Const::Array(items) if start <= end && *end < items.len() => {
+				Ok(Const::Array(items[*start..=*end].to_vec()))
+			},
+			c => Err(format!("invalid slice {}..{} of {:?}", start, end, c)),
+		},
+		ExprKind::Record(name, values) => {
+			let values: Result<Vec<(String, Const)>, String> = values.iter().map(|(field, e)| {
+				Ok((field.clone(), eval(e, consts)?))
+			}).collect();
+			Ok(Const::Record(name.clone(), values?))
+		},
+		ExprKind::Field(e, field) => match eval(e, consts)? {
+			Const::Record(name, values) => match values.into_iter().find(|(f, _)| f == field) {
+				Some((_, c)) => Ok(c),
+				None => Err(format!("record `{}` has no field `{}`", name, field)),
+			},
+			c => Err(format!("invalid field `{}` of {:?}", field, c)),
+		},
+		_ => Err("not a constant expression".to_string()),
+	}
+}
+
+/// Evaluates constant declarations, in order. Each constant can refer to the previous ones.
+pub fn eval_consts(decls: &[ConstDecl]) -> Result<Vec<(String, Const)>, CompileError> {
+	let mut consts = HashMap::new();
+	let mut values = Vec::new();
+	for decl in decls {
+		let c = match eval(&decl.value, &consts) {
+			Ok(c) => c,
+			Err(msg) => return error(CompileError::Type, &decl.value.span, format!("cannot evaluate constant `{}`: {}", &decl.name, msg)),
+		};
+		consts.insert(decl.name.clone(), c.clone());
+		values.push((decl.name.clone(), c));
+	}
+	Ok(values)
+}
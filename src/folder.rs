@@ -0,0 +1,243 @@
+// Constant folding simplifies the basic expressions of a normalized AST:
+//
+// - Unary and binary operators applied to constants are evaluated, the same way the generated code
+//   would: integer overflows and divisions by zero are left to be computed at run-time, and floats
+//   are computed with 32 bits (see `evaluator.rs`)
+// - `if` with a constant condition is replaced with one of its branches
+// - Equations defining a variable with a constant value are propagated: the variable is replaced
+//   with its value in the equations reading it, which may be folded in turn
+//
+// References to top-level constants are replaced with their value when they are folded, and left
+// as-is otherwise. Only booleans, numbers and enumerations are propagated, and variables are not
+// replaced where the normalized AST expects an atom (indexed arrays, iterator arguments, `current`
+// operands, reset conditions and clock variables).
+//
+// Equations whose variables are no longer read are then removed, except the ones defining an
+// output or calling a node from the source code. For instance, `o = if false then f(x) else g(x)`
+// becomes `o = tmp2` with `tmp2 = g(x)`, and the intermediate calling `f` is removed.
+//
+// Propagation uses a worklist: when a variable is found to be constant, only the equations which
+// read it are folded again.
+
+use std::collections::{HashMap, HashSet};
+use crate::nast::*;
+use crate::evaluator::{eval_binop, eval_unop};
+
+struct Folder<'a> {
+	/// Value of each top-level constant
+	globals: &'a HashMap<String, Const>,
+	/// Value of the variables defined by a constant
+	consts: HashMap<String, Const>,
+	/// Variables read by the equation being folded
+	reads: Vec<String>,
+}
+
+impl<'a> Folder<'a> {
+	/// Returns the value of an expression if it is a constant.
+	fn const_of(&self, e: &Bexpr) -> Option<Const> {
+		match e {
+			Bexpr::Atom(Atom::Const(c)) => Some(c.clone()),
+			Bexpr::Atom(Atom::Global(name)) => self.globals.get(name).cloned(),
+			// Sampling is only used by the clock calculus, which is already done
+			Bexpr::When(e, _, _) => self.const_of(e),
+			_ => None,
+		}
+	}
+
+	fn read(&mut self, name: &str) {
+		self.reads.push(name.to_string());
+	}
+
+	fn fold_atom(&mut self, a: &Atom) -> Atom {
+		if let Atom::Ident(name) = a {
+			self.read(name);
+		}
+		a.clone()
+	}
+
+	fn fold_bexprs(&mut self, exprs: &[Bexpr]) -> Vec<Bexpr> {
+		exprs.iter().map(|e| self.fold_bexpr(e)).collect()
+	}
+
+	fn fold_bexpr(&mut self, e: &Bexpr) -> Bexpr {
+		match e {
+			Bexpr::Atom(Atom::Ident(name)) => match self.consts.get(name) {
+				Some(c) => Bexpr::Atom(Atom::Const(c.clone())),
+				None => {
+					self.read(name);
+					e.clone()
+				},
+			},
+			Bexpr::Atom(_) => e.clone(),
+			Bexpr::Unop(op, e) => {
+				let e = self.fold_bexpr(e);
+				match self.const_of(&e).map(|c| eval_unop(*op, &c)) {
+					Some(Ok(c)) => Bexpr::Atom(Atom::Const(c)),
+					_ => Bexpr::Unop(*op, Box::new(e)),
+				}
+			},
+			Bexpr::Binop(op, exprs) => {
+				let (e1, e2): &(Bexpr, Bexpr) = exprs;
+				let (e1, e2) = (self.fold_bexpr(e1), self.fold_bexpr(e2));
+				match (self.const_of(&e1), self.const_of(&e2)) {
+					(Some(c1), Some(c2)) => match eval_binop(*op, &c1, &c2) {
+						Ok(c) => Bexpr::Atom(Atom::Const(c)),
+						Err(_) => Bexpr::Binop(*op, Box::new((e1, e2))),
+					},
+					_ => Bexpr::Binop(*op, Box::new((e1, e2))),
+				}
+			},
+			Bexpr::If(iff) => {
+				let (cond, body, else_part): &(Bexpr, Bexpr, Bexpr) = iff;
+				let cond = self.fold_bexpr(cond);
+				match self.const_of(&cond) {
+					Some(Const::Bool(true)) => self.fold_bexpr(body),
+					Some(Const::Bool(false)) => self.fold_bexpr(else_part),
+					_ => Bexpr::If(Box::new((cond, self.fold_bexpr(body), self.fold_bexpr(else_part)))),
+				}
+			},
+			Bexpr::Tuple(exprs) => Bexpr::Tuple(self.fold_bexprs(exprs)),
+			Bexpr::When(e, clock, value) => {
+				self.read(clock);
+				Bexpr::When(Box::new(self.fold_bexpr(e)), clock.clone(), value.clone())
+			},
+			Bexpr::Merge(clock, cases) => {
+				self.read(clock);
+				Bexpr::Merge(clock.clone(), cases.iter().map(|(value, e)| {
+					(value.clone(), self.fold_bexpr(e))
+				}).collect())
+			},
+			Bexpr::Array(exprs) => Bexpr::Array(self.fold_bexprs(exprs)),
+			Bexpr::Repeat(e, size) => Bexpr::Repeat(Box::new(self.fold_bexpr(e)), *size),
+			Bexpr::Index(a, i) => Bexpr::Index(self.fold_atom(a), Box::new(self.fold_bexpr(i))),
+			Bexpr::Record(name, values) => {
+				Bexpr::Record(name.clone(), values.iter().map(|(field, e)| {
+					(field.clone(), self.fold_bexpr(e))
+				}).collect())
+			},
+			Bexpr::Field(e, field) => Bexpr::Field(Box::new(self.fold_bexpr(e)), field.clone()),
+		}
+	}
+
+	fn fold_expr(&mut self, e: &Expr) -> Expr {
+		match e {
+			Expr::Bexpr(e) => Expr::Bexpr(self.fold_bexpr(e)),
+			Expr::Call{name, args, reset} => Expr::Call{
+				name: name.clone(),
+				args: self.fold_bexprs(args),
+				reset: reset.as_ref().map(|cond| self.fold_atom(cond)),
+			},
+			Expr::Iterate{iter, name, size, args} => Expr::Iterate{
+				iter: *iter,
+				name: name.clone(),
+				size: *size,
+				args: args.iter().map(|a| self.fold_atom(a)).collect(),
+			},
+			Expr::Fby(init, next) => Expr::Fby(init.clone(), self.fold_bexprs(next)),
+			Expr::Pre(next) => Expr::Pre(self.fold_bexprs(next)),
+			Expr::Arrow(first, next) => Expr::Arrow(self.fold_bexprs(first), self.fold_bexprs(next)),
+			Expr::Current(a) => Expr::Current(self.fold_atom(a)),
+			// Calls are only split by the scheduler
+			Expr::Outputs{..} | Expr::Step{..} => unreachable!(),
+		}
+	}
+
+	fn fold_clock(&mut self, c: &Clock) {
+		if let Clock::On(parent, clock, _) = c {
+			self.fold_clock(parent);
+			self.read(clock);
+		}
+	}
+
+	/// Folds an equation, and returns the variables it reads.
+	fn fold_equation(&mut self, eq: &mut Equation) -> Vec<String> {
+		self.reads.clear();
+		self.fold_clock(&eq.clock);
+		eq.body = self.fold_expr(&eq.body);
+		std::mem::take(&mut self.reads)
+	}
+}
+
+/// Returns whether a constant can be propagated.
+fn is_scalar(c: &Const) -> bool {
+	matches!(c, Const::Unit | Const::Bool(_) | Const::Int(_) | Const::Float(_) | Const::Enum(_, _))
+}
+
+fn fold_node(n: &Node, globals: &HashMap<String, Const>) -> Node {
+	let mut folder = Folder{globals, consts: HashMap::new(), reads: Vec::new()};
+	let mut body = n.body.clone();
+
+	// Equations reading each variable which is not known to be constant
+	let mut readers: HashMap<String, Vec<usize>> = HashMap::new();
+	let mut eq_reads = vec![Vec::new(); body.len()];
+	let mut todo: Vec<usize> = (0..body.len()).rev().collect();
+	while let Some(i) = todo.pop() {
+		eq_reads[i] = folder.fold_equation(&mut body[i]);
+		for name in &eq_reads[i] {
+			readers.entry(name.clone()).or_default().push(i);
+		}
+		let eq = &body[i];
+		if let (Expr::Bexpr(e), [name]) = (&eq.body, &eq.names[..]) {
+			match folder.const_of(e) {
+				Some(c) if is_scalar(&c) && !folder.consts.contains_key(name) => {
+					folder.consts.insert(name.clone(), c);
+					todo.extend(readers.remove(name).unwrap_or_default());
+				},
+				_ => {},
+			}
+		}
+	}
+
+	// Remove the equations which are not read anymore
+	let mut count: HashMap<&str, usize> = HashMap::new();
+	for name in eq_reads.iter().flatten() {
+		*count.entry(name).or_default() += 1;
+	}
+	let outputs: HashSet<&str> = n.args_out.iter().map(|(name, _)| name.as_str()).collect();
+	let is_dead = |eq: &Equation, count: &HashMap<&str, usize>| {
+		let removable = eq.intermediate || !matches!(eq.body, Expr::Call{..} | Expr::Iterate{..});
+		removable && eq.names.iter().all(|name| !outputs.contains(name.as_str()) && count.get(name.as_str()).is_none_or(|c| *c == 0))
+	};
+	let defs: HashMap<&str, usize> = body.iter().enumerate().flat_map(|(i, eq)| eq.names.iter().map(move |name| (name.as_str(), i))).collect();
+	let mut removed = vec![false; body.len()];
+	let mut todo: Vec<usize> = (0..body.len()).filter(|i| is_dead(&body[*i], &count)).collect();
+	while let Some(i) = todo.pop() {
+		if removed[i] {
+			continue;
+		}
+		removed[i] = true;
+		for name in &eq_reads[i] {
+			*count.get_mut(name.as_str()).unwrap() -= 1;
+		}
+		// The equations defining the variables it reads may be dead now
+		for name in &eq_reads[i] {
+			if let Some(j) = defs.get(name.as_str()) {
+				if !removed[*j] && is_dead(&body[*j], &count) {
+					todo.push(*j);
+				}
+			}
+		}
+	}
+	let removed_names: HashSet<&String> = body.iter().zip(removed.iter()).filter(|(_, r)| **r).flat_map(|(eq, _)| eq.names.iter()).collect();
+
+	Node{
+		name: n.name.clone(),
+		args_in: n.args_in.clone(),
+		args_out: n.args_out.clone(),
+		locals: n.locals.iter().filter(|(name, _)| !removed_names.contains(name)).cloned().collect(),
+		body: body.iter().zip(removed.iter()).filter(|(_, r)| !**r).map(|(eq, _)| eq.clone()).collect(),
+		groups: n.groups.clone(),
+		span: n.span.clone(),
+	}
+}
+
+/// Folds the constant expressions of each node.
+pub fn fold(f: &File) -> File {
+	let globals = f.consts.iter().map(|decl| (decl.name.clone(), decl.value.clone())).collect();
+	File{
+		types: f.types.clone(),
+		consts: f.consts.clone(),
+		externs: f.externs.clone(),
+		nodes: f.nodes.iter().map(|n| fold_node(n, &globals)).collect(),
+	}
+}
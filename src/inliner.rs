@@ -0,0 +1,241 @@
+// Inlining replaces calls to selected nodes with the equations of the callee, so that small helpers
+// like `exclusive_or` and `half_add` in `test/tutorial.mls` don't cost a function call, nor a
+// memory struct of their own. For instance:
+//
+// ```lustre
+// node half_add(a, b: bool) returns (s, co: bool);
+// let
+//   s = a xor b;
+//   co = a and b;
+// tel
+//
+// node top(x, y: bool) returns (s, co: bool);
+// let
+//   (s, co) = half_add(x, y);
+// tel
+// ```
+//
+// becomes, in the normalized AST:
+//
+// ```lustre
+// node top(x, y: bool) returns (s, co: bool);
+// var half_add_a, half_add_b: bool;
+// let
+//   half_add_a = x;
+//   half_add_b = y;
+//   s = half_add_a xor half_add_b;
+//   co = half_add_a and half_add_b;
+// tel
+// ```
+//
+// The inputs of the callee become local variables of the caller, defined by the arguments of the
+// call. Its outputs are replaced with the variables defined by the call. Its local variables are
+// renamed apart and added to the local variables of the caller, so that its `fby`, `pre` and `->`
+// memories become fields of the memory of the caller.
+//
+// This is done on the clocked AST: the equations of the callee are computed on the clock of the
+// call, which replaces the base clock of the callee.
+//
+// Nodes are inlined after the calls in their own body. Calls with a reset condition, iterators and
+// calls to recursive nodes are left as-is. Inlined nodes which are no longer called are removed,
+// except the last node of the file, which is called by `main()`.
+
+use std::collections::{HashMap, HashSet};
+use crate::nast::*;
+use crate::sequentializer::callees_first;
+
+/// Nodes to inline.
+pub enum Inline {
+	All,
+	Nodes(Vec<String>),
+}
+
+fn rename_atom(a: &Atom, names: &HashMap<String, String>) -> Atom {
+	match a {
+		Atom::Ident(name) => Atom::Ident(names[name].clone()),
+		Atom::Const(_) | Atom::Global(_) => a.clone(),
+	}
+}
+
+fn rename_bexprs(exprs: &[Bexpr], names: &HashMap<String, String>) -> Vec<Bexpr> {
+	exprs.iter().map(|e| rename_bexpr(e, names)).collect()
+}
+
+fn rename_bexpr(e: &Bexpr, names: &HashMap<String, String>) -> Bexpr {
+	match e {
+		Bexpr::Atom(a) => Bexpr::Atom(rename_atom(a, names)),
+		Bexpr::Unop(op, e) => Bexpr::Unop(*op, Box::new(rename_bexpr(e, names))),
+		Bexpr::Binop(op, exprs) => {
+			let (e1, e2): &(Bexpr, Bexpr) = exprs;
+			Bexpr::Binop(*op, Box::new((rename_bexpr(e1, names), rename_bexpr(e2, names))))
+		},
+		Bexpr::If(iff) => {
+			let (cond, body, else_part): &(Bexpr, Bexpr, Bexpr) = iff;
+			Bexpr::If(Box::new((
+				rename_bexpr(cond, names),
+				rename_bexpr(body, names),
+				rename_bexpr(else_part, names),
+			)))
+		},
+		Bexpr::Tuple(exprs) => Bexpr::Tuple(rename_bexprs(exprs, names)),
+		Bexpr::When(e, clock, value) => {
+			Bexpr::When(Box::new(rename_bexpr(e, names)), names[clock].clone(), value.clone())
+		},
+		Bexpr::Merge(clock, cases) => {
+			Bexpr::Merge(names[clock].clone(), cases.iter().map(|(value, e)| {
+				(value.clone(), rename_bexpr(e, names))
+			}).collect())
+		},
+		Bexpr::Array(exprs) => Bexpr::Array(rename_bexprs(exprs, names)),
+		Bexpr::Repeat(e, size) => Bexpr::Repeat(Box::new(rename_bexpr(e, names)), *size),
+		Bexpr::Index(a, i) => Bexpr::Index(rename_atom(a, names), Box::new(rename_bexpr(i, names))),
+		Bexpr::Record(name, values) => {
+			Bexpr::Record(name.clone(), values.iter().map(|(field, e)| {
+				(field.clone(), rename_bexpr(e, names))
+			}).collect())
+		},
+		Bexpr::Field(e, field) => Bexpr::Field(Box::new(rename_bexpr(e, names)), field.clone()),
+	}
+}
+
+fn rename_expr(e: &Expr, names: &HashMap<String, String>) -> Expr {
+	match e {
+		Expr::Bexpr(e) => Expr::Bexpr(rename_bexpr(e, names)),
+		Expr::Call{name, args, reset} => Expr::Call{
+			name: name.clone(),
+			args: rename_bexprs(args, names),
+			reset: reset.as_ref().map(|cond| rename_atom(cond, names)),
+		},
+		Expr::Iterate{iter, name, size, args} => Expr::Iterate{
+			iter: *iter,
+			name: name.clone(),
+			size: *size,
+			args: args.iter().map(|a| rename_atom(a, names)).collect(),
+		},
+		Expr::Fby(init, next) => Expr::Fby(init.clone(), rename_bexprs(next, names)),
+		Expr::Pre(next) => Expr::Pre(rename_bexprs(next, names)),
+		Expr::Arrow(first, next) => Expr::Arrow(rename_bexprs(first, names), rename_bexprs(next, names)),
+		Expr::Current(a) => Expr::Current(rename_atom(a, names)),
+		// Calls are only split by the scheduler
+		Expr::Outputs{..} | Expr::Step{..} => unreachable!(),
+	}
+}
+
+/// Places a clock of the callee on the clock of the call.
+fn rename_clock(c: &Clock, call: &Clock, names: &HashMap<String, String>) -> Clock {
+	match c {
+		Clock::Base => call.clone(),
+		Clock::On(parent, clock, value) => {
+			Clock::On(Box::new(rename_clock(parent, call, names)), names[clock].clone(), value.clone())
+		},
+	}
+}
+
+/// Returns a name for a variable of an inlined callee which is not used by the caller.
+fn fresh_name(taken: &mut HashSet<String>, callee: &str, name: &str) -> String {
+	let mut fresh = format!("{}_{}", callee, name);
+	let mut i = 2;
+	while taken.contains(&fresh) {
+		fresh = format!("{}_{}_{}", callee, name, i);
+		i += 1;
+	}
+	taken.insert(fresh.clone());
+	fresh
+}
+
+/// Adds the equations of a callee to the body of the caller, in place of the call `eq`.
+fn inline_call(eq: &Equation, callee: &Node, args: &[Bexpr], taken: &mut HashSet<String>, locals: &mut Vec<(String, Type)>, body: &mut Vec<Equation>) {
+	let mut names: HashMap<String, String> = callee.args_out.iter().map(|(name, _)| name.clone()).zip(eq.names.iter().cloned()).collect();
+	for (name, t) in callee.args_in.iter().chain(callee.locals.iter()) {
+		let fresh = fresh_name(taken, &callee.name, name);
+		locals.push((fresh.clone(), t.clone()));
+		names.insert(name.clone(), fresh);
+	}
+
+	for ((name, _), arg) in callee.args_in.iter().zip(args.iter()) {
+		body.push(Equation{
+			names: vec![names[name].clone()],
+			body: Expr::Bexpr(arg.clone()),
+			clock: eq.clock.clone(),
+			span: eq.span.clone(),
+			intermediate: false,
+		});
+	}
+	for callee_eq in &callee.body {
+		body.push(Equation{
+			names: callee_eq.names.iter().map(|name| names[name].clone()).collect(),
+			body: rename_expr(&callee_eq.body, &names),
+			clock: rename_clock(&callee_eq.clock, &eq.clock, &names),
+			span: callee_eq.span.clone(),
+			intermediate: callee_eq.intermediate,
+		});
+	}
+}
+
+/// Inlines the calls to the nodes of `callees` in a node, and adds the names of the inlined nodes
+/// to `inlined`.
+fn inline_node(n: &Node, callees: &HashMap<String, Node>, inlined: &mut HashSet<String>) -> Node {
+	let vars = n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter());
+	let mut taken: HashSet<String> = vars.map(|(name, _)| name.clone()).collect();
+	let mut locals = n.locals.clone();
+	let mut body = Vec::new();
+	for eq in &n.body {
+		if let Expr::Call{name, args, reset: None} = &eq.body {
+			if let Some(callee) = callees.get(name) {
+				if callee.args_in.len() == args.len() && callee.args_out.len() == eq.names.len() {
+					inline_call(eq, callee, args, &mut taken, &mut locals, &mut body);
+					inlined.insert(name.clone());
+					continue;
+				}
+			}
+		}
+		body.push(eq.clone());
+	}
+	Node{
+		name: n.name.clone(),
+		args_in: n.args_in.clone(),
+		args_out: n.args_out.clone(),
+		locals,
+		body,
+		groups: n.groups.clone(),
+		span: n.span.clone(),
+	}
+}
+
+/// Inlines the calls to the selected nodes. Selected nodes which aren't in the file, because they
+/// have errors, are ignored.
+pub fn inline(f: &File, selected: &Inline) -> File {
+	let is_selected = |name: &str| match selected {
+		Inline::All => true,
+		Inline::Nodes(names) => names.iter().any(|selected| selected == name),
+	};
+
+	let mut nodes: Vec<Option<Node>> = vec![None; f.nodes.len()];
+	let mut callees = HashMap::new();
+	let mut inlined = HashSet::new();
+	for i in callees_first(f) {
+		let n = inline_node(&f.nodes[i], &callees, &mut inlined);
+		if is_selected(&n.name) {
+			callees.insert(n.name.clone(), n.clone());
+		}
+		nodes[i] = Some(n);
+	}
+	let nodes: Vec<Node> = nodes.into_iter().map(Option::unwrap).collect();
+
+	// Remove the inlined nodes which are not called anymore
+	let called: HashSet<&str> = nodes.iter().flat_map(|n| n.body.iter()).filter_map(|eq| match &eq.body {
+		Expr::Call{name, ..} | Expr::Iterate{name, ..} => Some(name.as_str()),
+		_ => None,
+	}).collect();
+	let last = f.nodes.last().map(|n| n.name.as_str());
+	let nodes = nodes.iter().filter(|n| {
+		!inlined.contains(&n.name) || called.contains(n.name.as_str()) || Some(n.name.as_str()) == last
+	}).cloned().collect();
+
+	File{
+		types: f.types.clone(),
+		consts: f.consts.clone(),
+		externs: f.externs.clone(),
+		nodes,
+	}
+}
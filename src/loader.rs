@@ -0,0 +1,134 @@
+// Loader reads a file and all the files it loads, and merges their declarations
+//
+// A file can load other files at its beginning:
+//
+// ```lustre
+// package filters;
+// include "lib/maths.lus";
+// uses geometry, physics;
+// ```
+//
+// - `include "path";` loads the file at `path`, relative to the including file
+// - `uses name;` loads the package `name`, which is the file `name.lus` next to the including
+//   file. This file must start with `package name;`
+//
+// Loaded files are parsed before the file which loads them, so that their types and constants can
+// be used in it. Their declarations are added before the ones of the file, and all the nodes end up
+// in the same namespace: the type checker reports nodes which are defined twice, and calls to
+// unknown nodes, with the file they are written in.
+//
+// Each file is only loaded once, even if several files load it. A file which (indirectly) loads
+// itself is an error.
+//
+// The prelude, which declares the functions implemented by the runtime, is loaded before any other
+// file.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::ast::*;
+use crate::diagnostic::{CompileError, Source};
+use crate::parser::{Import, header, parse};
+
+/// Declarations of the functions implemented by the runtime.
+const PRELUDE: &str = include_str!("runtime/prelude.lus");
+
+struct Loader {
+	/// Declarations of the files loaded so far
+	file: File,
+	/// Files being loaded, from the first one to the current one
+	loading: Vec<PathBuf>,
+	/// Files which have been loaded
+	loaded: HashSet<PathBuf>,
+}
+
+fn append(f: &mut File, mut other: File) {
+	f.types.append(&mut other.types);
+	f.consts.append(&mut other.consts);
+	f.externs.append(&mut other.externs);
+	f.generics.append(&mut other.generics);
+	f.nodes.append(&mut other.nodes);
+}
+
+impl Loader {
+	fn new() -> Result<Self, CompileError> {
+		let mut loader = Loader{
+			file: File{
+				types: Vec::new(),
+				consts: Vec::new(),
+				externs: Vec::new(),
+				generics: Vec::new(),
+				nodes: Vec::new(),
+			},
+			loading: Vec::new(),
+			loaded: HashSet::new(),
+		};
+		loader.load_source(PRELUDE, Path::new("prelude.lus"), None)?;
+		Ok(loader)
+	}
+
+	/// Loads the file at `path`, which must declare `package` if any.
+	fn load_file(&mut self, path: &Path, package: Option<&str>) -> Result<(), CompileError> {
+		let canonical = match fs::canonicalize(path) {
+			Ok(canonical) => canonical,
+			Err(err) => return Err(CompileError::Load(format!("cannot read `{}`: {}", path.display(), err))),
+		};
+		if self.loaded.contains(&canonical) {
+			return Ok(());
+		}
+		if self.loading.contains(&canonical) {
+			return Err(CompileError::Load(format!("file `{}` loads itself", path.display())));
+		}
+		let input = match fs::read_to_string(path) {
+			Ok(input) => input,
+			Err(err) => return Err(CompileError::Load(format!("cannot read `{}`: {}", path.display(), err))),
+		};
+		self.loading.push(canonical.clone());
+		self.load_source(&input, path, package)?;
+		self.loading.pop();
+		self.loaded.insert(canonical);
+		Ok(())
+	}
+
+	/// Loads the files imported by `input`, which is the content of the file at `path`, and then
+	/// its own declarations.
+	fn load_source(&mut self, input: &str, path: &Path, package: Option<&str>) -> Result<(), CompileError> {
+		let source = Rc::new(Source::new(path.display().to_string(), input.to_string()));
+		let h = header(&source)?;
+		if let Some(package) = package {
+			if h.package.as_deref() != Some(package) {
+				return Err(CompileError::Load(format!("file `{}` does not declare package `{}`", &source.name, package)));
+			}
+		}
+
+		let dir = path.parent().unwrap_or_else(|| Path::new(""));
+		for import in &h.imports {
+			match import {
+				Import::Include(file) => self.load_file(&dir.join(file), None)?,
+				Import::Uses(package) => {
+					self.load_file(&dir.join(format!("{}.lus", package)), Some(package))?
+				},
+			}
+		}
+
+		let f = parse(&source, &self.file)?;
+		append(&mut self.file, f);
+		Ok(())
+	}
+}
+
+/// Loads the file at `path`, and the files it loads.
+pub fn load(path: &Path) -> Result<File, CompileError> {
+	let mut loader = Loader::new()?;
+	loader.load_file(path, None)?;
+	Ok(loader.file)
+}
+
+/// Loads a file read from the standard input. The files it loads are relative to the current
+/// directory.
+pub fn load_stdin(input: &str) -> Result<File, CompileError> {
+	let mut loader = Loader::new()?;
+	loader.load_source(input, Path::new("<stdin>"), None)?;
+	Ok(loader.file)
+}
@@ -0,0 +1,452 @@
+// Lowering compiles the automata of a raw AST into regular equations, before type checking.
+//
+// An automaton is a set of states, each with its own equations. For instance:
+//
+// ```lustre
+// automaton
+//   state Off
+//     do x = 0;
+//     until on then On
+//   state On
+//     unless off continue Off
+//     do x = 0 fby x + 1;
+// end
+// ```
+//
+// Each automaton gets an enumeration with one value per state, and these variables:
+//
+// - `pst` and `pnr` hold the state selected at the end of the previous instant, and whether it
+//   must be restarted. The initial state is the first one, and it is restarted
+// - `st` and `sr` are the active state of the current instant and whether it is restarted. They
+//   are computed from `pst` and `pnr` with the strong transitions (`unless`) of the state `pst`
+// - `nst` and `nr` are the state selected for the next instant, computed with the weak
+//   transitions (`until`) of the active state
+//
+// The equations of each state are computed on the clock `S(st)`, and the variables they define are
+// renamed: the variable `x` of the example is defined by `x = merge st (Off -> x_off) (On -> x_on)`.
+// The other variables they use are sampled on the clock of the state. The local variables of each
+// state are renamed too.
+//
+// Since equations computed on a sub-clock keep their memory while they are not active, states are
+// resumed by default. When a state is restarted with a `then` transition, the `fby` and `->`
+// operators of its equations yield their initial value. Nested automata are lowered first: when
+// the state they belong to is restarted, they go back to their initial state, which is restarted
+// too, and so are the nodes called by the state.
+//
+// Each variable defined in a state of an automaton must be defined in all of its states.
+
+use std::collections::{HashMap, HashSet};
+use crate::ast::*;
+use crate::diagnostic::{CompileError, Diagnostic, collect, error};
+
+/// Lowering of the automata of a node.
+struct Lowerer<'a> {
+	node: &'a Node,
+	/// Names which cannot be used for new variables
+	names: HashSet<String>,
+	/// Names which cannot be used for new types
+	type_names: &'a mut HashSet<String>,
+	/// Type of each variable, including the local variables of states
+	types: HashMap<String, Type>,
+	/// New local variables of the node
+	locals: Vec<(String, Type)>,
+	/// New enumerations, one per automaton
+	type_decls: Vec<TypeDecl>,
+}
+
+/// Renaming of the variables used by the equations of a state.
+struct Renaming {
+	/// Clock variable and value on which the equations are computed
+	clock: String,
+	value: Const,
+	/// Suffix of the new variables
+	suffix: String,
+	/// New name of each variable defined in the state
+	names: HashMap<String, String>,
+	/// Sampled copy of each variable defined outside of the state
+	copies: HashMap<String, String>,
+	/// Whether the state is restarted, if memories are reset
+	reset: Option<Expr>,
+}
+
+fn fresh_name(base: &str, names: &mut HashSet<String>) -> String {
+	let mut name = base.to_string();
+	let mut i = 1;
+	while names.contains(&name) {
+		name = format!("{}_{}", base, i);
+		i += 1;
+	}
+	names.insert(name.clone());
+	name
+}
+
+fn collect_state_locals(automata: &[Automaton], names: &mut HashSet<String>) {
+	for a in automata {
+		for s in &a.states {
+			for (name, _) in &s.locals {
+				names.insert(name.clone());
+			}
+			collect_state_locals(&s.automata, names);
+		}
+	}
+}
+
+/// Builds a generated expression, which stands for the source code at `span`.
+fn expr(kind: ExprKind, span: &Span) -> Expr {
+	Expr{kind, span: span.clone()}
+}
+
+/// Builds a variable sampled on a state of an automaton (`x when S(st)`).
+fn sampled(name: &str, clock: &str, value: Const, span: &Span) -> Expr {
+	expr(ExprKind::When(Box::new(expr(ExprKind::Ident(name.to_string()), span)), clock.to_string(), value), span)
+}
+
+impl<'a> Lowerer<'a> {
+	fn fresh(&mut self, base: &str, t: Type) -> String {
+		let name = fresh_name(base, &mut self.names);
+		self.types.insert(name.clone(), t.clone());
+		self.locals.push((name.clone(), t));
+		name
+	}
+
+	/// Returns the name of a variable in a state, and samples it if needed.
+	fn var(&mut self, name: &str, span: &Span, r: &mut Renaming, eqs: &mut Vec<Equation>) -> String {
+		if let Some(new_name) = r.names.get(name).or_else(|| r.copies.get(name)) {
+			return new_name.clone();
+		}
+		let t = match self.types.get(name) {
+			Some(t) => t.clone(),
+			None => return name.to_string(), // Reported by the type checker
+		};
+		let copy = self.fresh(&format!("{}_{}", name, &r.suffix), t);
+		eqs.push(Equation{
+			names: vec![copy.clone()],
+			body: sampled(name, &r.clock, r.value.clone(), span),
+			span: span.clone(),
+		});
+		r.copies.insert(name.to_string(), copy.clone());
+		copy
+	}
+
+	fn rename(&mut self, e: &Expr, r: &mut Renaming, eqs: &mut Vec<Equation>) -> Expr {
+		let mut rename_all = |exprs: &[Expr], r: &mut Renaming, eqs: &mut Vec<Equation>| -> Vec<Expr> {
+			exprs.iter().map(|e| self.rename(e, r, eqs)).collect()
+		};
+		let span = &e.span;
+		let kind = match &e.kind {
+			ExprKind::Call{name, args, reset} => {
+				let args = rename_all(args, r, eqs);
+				let cond = reset.as_ref().map(|cond| self.rename(cond, r, eqs));
+				// Called nodes are reset with the state
+				let reset = match (&r.reset, cond) {
+					(Some(state_reset), Some(cond)) => {
+						Some(expr(ExprKind::Binop(Binop::Or, Box::new((state_reset.clone(), cond))), span))
+					},
+					(Some(state_reset), None) => Some(state_reset.clone()),
+					(None, cond) => cond,
+				};
+				ExprKind::Call{name: name.clone(), args, reset: reset.map(Box::new)}
+			},
+			ExprKind::Iterate{iter, name, size, args} => ExprKind::Iterate{
+				iter: *iter,
+				name: name.clone(),
+				size: *size,
+				args: rename_all(args, r, eqs),
+			},
+			ExprKind::Const(_) | ExprKind::Global(_) => e.kind.clone(),
+			ExprKind::Unop(op, e) => ExprKind::Unop(*op, Box::new(self.rename(e, r, eqs))),
+			ExprKind::Binop(op, exprs) => {
+				let (e1, e2): &(Expr, Expr) = exprs;
+				ExprKind::Binop(*op, Box::new((self.rename(e1, r, eqs), self.rename(e2, r, eqs))))
+			},
+			ExprKind::Fby(fby) => {
+				let (e1, e2): &(Expr, Expr) = fby;
+				let init = self.rename(e1, r, eqs);
+				let e = expr(ExprKind::Fby(Box::new((init.clone(), self.rename(e2, r, eqs)))), span);
+				match &r.reset {
+					Some(reset) => ExprKind::If(Box::new((reset.clone(), init, e))),
+					None => return e,
+				}
+			},
+			ExprKind::Pre(e) => ExprKind::Pre(Box::new(self.rename(e, r, eqs))),
+			ExprKind::Arrow(arrow) => {
+				let (e1, e2): &(Expr, Expr) = arrow;
+				let first = self.rename(e1, r, eqs);
+				let e = expr(ExprKind::Arrow(Box::new((first.clone(), self.rename(e2, r, eqs)))), span);
+				match &r.reset {
+					Some(reset) => ExprKind::If(Box::new((reset.clone(), first, e))),
+					None => return e,
+				}
+			},
+			ExprKind::If(iff) => {
+				let (cond, body, else_part): &(Expr, Expr, Expr) = iff;
+				ExprKind::If(Box::new((
+					self.rename(cond, r, eqs),
+					self.rename(body, r, eqs),
+					self.rename(else_part, r, eqs),
+				)))
+			},
+			ExprKind::When(e, clock, value) => {
+				let e = self.rename(e, r, eqs);
+				ExprKind::When(Box::new(e), self.var(clock, span, r, eqs), value.clone())
+			},
+			ExprKind::Merge(clock, cases) => {
+				let clock = self.var(clock, span, r, eqs);
+				ExprKind::Merge(clock, cases.iter().map(|(value, e)| (value.clone(), self.rename(e, r, eqs))).collect())
+			},
+			ExprKind::Current(e) => ExprKind::Current(Box::new(self.rename(e, r, eqs))),
+			ExprKind::Ident(name) => ExprKind::Ident(self.var(name, span, r, eqs)),
+			ExprKind::Tuple(exprs) => ExprKind::Tuple(rename_all(exprs, r, eqs)),
+			ExprKind::Array(exprs) => ExprKind::Array(rename_all(exprs, r, eqs)),
+			ExprKind::Repeat(e, size) => ExprKind::Repeat(Box::new(self.rename(e, r, eqs)), *size),
+			ExprKind::Index(index) => {
+				let (e, i): &(Expr, Expr) = index;
+				ExprKind::Index(Box::new((self.rename(e, r, eqs), self.rename(i, r, eqs))))
+			},
+			ExprKind::Slice(e, start, end) => ExprKind::Slice(Box::new(self.rename(e, r, eqs)), *start, *end),
+			ExprKind::Record(name, values) => {
+				ExprKind::Record(name.clone(), values.iter().map(|(field, e)| (field.clone(), self.rename(e, r, eqs))).collect())
+			},
+			ExprKind::Field(e, field) => ExprKind::Field(Box::new(self.rename(e, r, eqs)), field.clone()),
+		};
+		expr(kind, span)
+	}
+
+	/// Builds the next state and restart flag from the transitions of a state.
+	fn transitions(&mut self, transitions: &[Transition], otherwise: Expr, state: &dyn Fn(&str, &Span) -> Expr, r: &mut Renaming, eqs: &mut Vec<Equation>) -> Expr {
+		let mut e = otherwise;
+		for t in transitions.iter().rev() {
+			let restart = expr(ExprKind::Const(Const::Bool(t.restart)), &t.span);
+			let next = expr(ExprKind::Tuple(vec![state(&t.target, &t.span), restart]), &t.span);
+			e = expr(ExprKind::If(Box::new((self.rename(&t.cond, r, eqs), next, e))), &t.span);
+		}
+		e
+	}
+
+	/// Lowers an automaton, and returns its equations and the variables it defines. `reset` is the
+	/// restart flag of the state the automaton belongs to, if any.
+	fn lower_automaton(&mut self, a: &Automaton, reset: Option<Expr>) -> Result<(Vec<Equation>, Vec<String>), CompileError> {
+		let state_names: Vec<String> = a.states.iter().map(|s| s.name.clone()).collect();
+		let mut errors = Vec::new();
+		for (i, s) in a.states.iter().enumerate() {
+			if state_names[..i].contains(&s.name) {
+				let msg = format!("state `{}` is defined twice", &s.name);
+				errors.push(CompileError::Definition(Diagnostic::new(&s.span, msg)));
+			}
+			for t in s.unless.iter().chain(s.until.iter()) {
+				if !state_names.contains(&t.target) {
+					let msg = format!("unknown state `{}`", &t.target);
+					errors.push(CompileError::UnknownName(Diagnostic::new(&t.span, msg)));
+				}
+			}
+		}
+		collect(errors)?;
+
+		let type_name = fresh_name(&format!("{}_state", &self.node.name), self.type_names);
+		self.type_decls.push(TypeDecl{name: type_name.clone(), def: TypeDef::Enum(state_names.clone()), span: a.span.clone()});
+		let t = Type::Enum(type_name.clone());
+		let value = |name: &str| Const::Enum(type_name.clone(), name.to_string());
+		let state = |name: &str, span: &Span| expr(ExprKind::Const(value(name)), span);
+
+		let st = self.fresh("st", t.clone());
+		let sr = self.fresh("sr", Type::Bool);
+		let pst = self.fresh("pst", t.clone());
+		let pnr = self.fresh("pnr", Type::Bool);
+		let nst = self.fresh("nst", t);
+		let nr = self.fresh("nr", Type::Bool);
+
+		// Lower nested automata, and find the variables defined by each state
+		let mut bodies = Vec::new();
+		for s in &a.states {
+			for (name, t) in &s.locals {
+				self.types.insert(name.clone(), t.clone());
+			}
+			let n_locals = self.locals.len();
+			let mut inner_body = Vec::new();
+			let mut defined: Vec<String> = s.body.iter().flat_map(|eq| eq.names.clone()).collect();
+			for inner in &s.automata {
+				let sr_s = sampled(&sr, &st, value(&s.name), &inner.span);
+				let (eqs, inner_defined) = self.lower_automaton(inner, Some(sr_s))?;
+				inner_body.extend(eqs);
+				defined.extend(inner_defined);
+			}
+			let generated: Vec<String> = self.locals[n_locals..].iter().map(|(name, _)| name.clone()).collect();
+			defined.retain(|name| !s.locals.iter().any(|(local, _)| local == name) && !generated.contains(name));
+			bodies.push((inner_body, defined, generated));
+		}
+
+		let mut shared: Vec<String> = Vec::new();
+		for (_, defined, _) in &bodies {
+			for name in defined {
+				if !shared.contains(name) {
+					shared.push(name.clone());
+				}
+			}
+		}
+		for (s, (_, defined, _)) in a.states.iter().zip(bodies.iter()) {
+			for name in &shared {
+				if !defined.contains(name) {
+					return error(CompileError::Definition, &s.span, format!("variable `{}` is not defined in state `{}`", name, &s.name));
+				}
+			}
+		}
+
+		let mut eqs = Vec::new();
+		let mut strong_cases = Vec::new();
+		let mut weak_cases = Vec::new();
+		let mut merges: Vec<Vec<(Const, Expr)>> = shared.iter().map(|_| Vec::new()).collect();
+		for (s, (inner_body, _, generated)) in a.states.iter().zip(bodies) {
+			let suffix = s.name.to_lowercase();
+
+			// Strong transitions are computed on the clock of the previous state
+			let mut r = Renaming{
+				clock: pst.clone(),
+				value: value(&s.name),
+				suffix: suffix.clone(),
+				names: HashMap::new(),
+				copies: HashMap::new(),
+				reset: None,
+			};
+			let pnr_s = sampled(&pnr, &pst, value(&s.name), &s.span);
+			let otherwise = expr(ExprKind::Tuple(vec![state(&s.name, &s.span), pnr_s]), &s.span);
+			let strong = self.transitions(&s.unless, otherwise, &state, &mut r, &mut eqs);
+			strong_cases.push((value(&s.name), strong));
+
+			// Equations and weak transitions are computed on the clock of the active state
+			let mut names = HashMap::new();
+			for (i, name) in shared.iter().enumerate() {
+				let t = match self.types.get(name) {
+					Some(t) => t.clone(),
+					None => return error(CompileError::UnknownName, &s.span, format!("variable `{}` is not declared", name)),
+				};
+				let new_name = self.fresh(&format!("{}_{}", name, &suffix), t);
+				merges[i].push((value(&s.name), expr(ExprKind::Ident(new_name.clone()), &s.span)));
+				names.insert(name.clone(), new_name);
+			}
+			for (name, t) in &s.locals {
+				let new_name = self.fresh(&format!("{}_{}", name, &suffix), t.clone());
+				names.insert(name.clone(), new_name);
+			}
+			// Generated variables are already on the right clock, and the restart flags of nested
+			// automata use the active state
+			for name in generated.into_iter().chain(vec![st.clone(), sr.clone()]) {
+				names.insert(name.clone(), name);
+			}
+			let sr_s = sampled(&sr, &st, value(&s.name), &s.span);
+			let mut r = Renaming{
+				clock: st.clone(),
+				value: value(&s.name),
+				suffix,
+				names,
+				copies: HashMap::new(),
+				reset: Some(sr_s),
+			};
+			for eq in &s.body {
+				let eq = Equation{
+					names: eq.names.iter().map(|name| r.names[name].clone()).collect(),
+					body: self.rename(&eq.body, &mut r, &mut eqs),
+					span: eq.span.clone(),
+				};
+				eqs.push(eq);
+			}
+			// Nested automata are already reset with the state
+			let reset = r.reset.take();
+			for eq in &inner_body {
+				let eq = Equation{
+					names: eq.names.iter().map(|name| r.names[name].clone()).collect(),
+					body: self.rename(&eq.body, &mut r, &mut eqs),
+					span: eq.span.clone(),
+				};
+				eqs.push(eq);
+			}
+			r.reset = reset;
+			let no_restart = expr(ExprKind::Const(Const::Bool(false)), &s.span);
+			let otherwise = expr(ExprKind::Tuple(vec![state(&s.name, &s.span), no_restart]), &s.span);
+			let weak = self.transitions(&s.until, otherwise, &state, &mut r, &mut eqs);
+			weak_cases.push((value(&s.name), weak));
+		}
+
+		let span = &a.span;
+		let ident = |name: &str| expr(ExprKind::Ident(name.to_string()), span);
+		let init = state(&a.states[0].name, span);
+		let mut pst_body = expr(ExprKind::Fby(Box::new((init.clone(), ident(&nst)))), span);
+		let restart = expr(ExprKind::Const(Const::Bool(true)), span);
+		let mut pnr_body = expr(ExprKind::Fby(Box::new((restart.clone(), ident(&nr)))), span);
+		if let Some(reset) = reset {
+			pst_body = expr(ExprKind::If(Box::new((reset.clone(), init, pst_body))), span);
+			pnr_body = expr(ExprKind::If(Box::new((reset, restart, pnr_body))), span);
+		}
+		let eq = |names: Vec<String>, body: Expr| Equation{names, body, span: span.clone()};
+		eqs.push(eq(vec![pst.clone()], pst_body));
+		eqs.push(eq(vec![pnr], pnr_body));
+		eqs.push(eq(vec![st.clone(), sr], expr(ExprKind::Merge(pst, strong_cases), span)));
+		eqs.push(eq(vec![nst, nr], expr(ExprKind::Merge(st.clone(), weak_cases), span)));
+		for (name, cases) in shared.iter().zip(merges) {
+			eqs.push(eq(vec![name.clone()], expr(ExprKind::Merge(st.clone(), cases), span)));
+		}
+		Ok((eqs, shared))
+	}
+}
+
+fn lower_node(n: &Node, type_names: &mut HashSet<String>) -> Result<(Node, Vec<TypeDecl>), CompileError> {
+	if n.automata.is_empty() {
+		return Ok((n.clone(), Vec::new()));
+	}
+
+	let vars = n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter());
+	let mut names: HashSet<String> = vars.clone().map(|(name, _)| name.clone()).collect();
+	collect_state_locals(&n.automata, &mut names);
+	let mut lowerer = Lowerer{
+		node: n,
+		names,
+		type_names,
+		types: vars.cloned().collect(),
+		locals: Vec::new(),
+		type_decls: Vec::new(),
+	};
+	let mut body = n.body.clone();
+	for a in &n.automata {
+		let (eqs, _) = lowerer.lower_automaton(a, None)?;
+		body.extend(eqs);
+	}
+
+	let mut locals = n.locals.clone();
+	locals.extend(lowerer.locals);
+	let node = Node{
+		name: n.name.clone(),
+		args_in: n.args_in.clone(),
+		args_out: n.args_out.clone(),
+		locals,
+		body,
+		automata: Vec::new(),
+		span: n.span.clone(),
+	};
+	Ok((node, lowerer.type_decls))
+}
+
+/// Lowers the automata of each node. Errors are added to `errors`, and the nodes with errors are
+/// kept without their body, so that calls to them can still be type-checked.
+pub fn lower(f: &File, errors: &mut Vec<CompileError>) -> File {
+	let mut type_names = f.types.iter().map(|decl| decl.name.clone()).collect();
+	let mut types = f.types.clone();
+	let mut nodes = Vec::new();
+	for n in &f.nodes {
+		match lower_node(n, &mut type_names) {
+			Ok((n, mut type_decls)) => {
+				types.append(&mut type_decls);
+				nodes.push(n);
+			},
+			Err(err) => {
+				errors.push(err);
+				nodes.push(Node{body: Vec::new(), automata: Vec::new(), ..n.clone()});
+			},
+		}
+	}
+	File{
+		types,
+		consts: f.consts.clone(),
+		externs: f.externs.clone(),
+		generics: f.generics.clone(),
+		nodes,
+	}
+}
@@ -3,6 +3,14 @@ extern crate pest;
 extern crate pest_derive;
 
 mod ast;
+mod clocker;
+mod definer;
+mod diagnostic;
+mod evaluator;
+mod folder;
+mod inliner;
+mod loader;
+mod lowerer;
 mod nast;
 mod normalizer;
 mod parser;
@@ -10,24 +18,164 @@ mod rustfmt;
 mod typer;
 mod sequentializer;
 
+use std::collections::HashSet;
+use std::env;
 use std::io::{Read, stdout, stdin};
-use crate::parser::parse;
+use std::path::Path;
+use std::process;
+use crate::diagnostic::{CompileError, collect};
+use crate::definer::check_definitions;
+use crate::folder::fold;
+use crate::inliner::{Inline, inline};
+use crate::loader::{load, load_stdin};
+use crate::lowerer::lower;
 use crate::rustfmt::format;
+use crate::typer::check;
 use crate::normalizer::normalize;
+use crate::clocker::clock;
 use crate::sequentializer::sequentialize;
 
-fn main() {
-	let mut buffer = String::new();
-	stdin().read_to_string(&mut buffer).unwrap();
+/// Compiles the file given as argument, or the standard input, and writes the generated code to
+/// the standard output. With `--inline`, all the nodes are inlined, and with `--inline=f,g`, only
+/// the nodes `f` and `g` are.
+fn compile() -> Result<(), CompileError> {
+	let mut path = None;
+	let mut inlined = None;
+	for arg in env::args().skip(1) {
+		if arg == "--inline" {
+			inlined = Some(Inline::All);
+		} else if let Some(names) = arg.strip_prefix("--inline=") {
+			inlined = Some(Inline::Nodes(names.split(',').map(|name| name.to_string()).collect()));
+		} else if arg.starts_with("--") || path.is_some() {
+			return Err(CompileError::Usage(format!("unexpected argument `{}`", arg)));
+		} else {
+			path = Some(arg);
+		}
+	}
 
-	let f = parse(&buffer).unwrap();
+	let f = match path {
+		Some(path) => load(Path::new(&path))?,
+		None => {
+			let mut buffer = String::new();
+			if let Err(err) = stdin().read_to_string(&mut buffer) {
+				return Err(CompileError::Load(format!("cannot read the standard input: {}", err)));
+			}
+			load_stdin(&buffer)?
+		},
+	};
 	eprintln!("parsed: {:?}", &f);
 
-	let nf = normalize(&f);
+	if let Some(Inline::Nodes(names)) = &inlined {
+		if let Some(name) = names.iter().find(|name| !f.nodes.iter().any(|n| &n.name == *name)) {
+			return Err(CompileError::Usage(format!("cannot inline unknown node `{}`", name)));
+		}
+	}
+
+	// The semantic passes keep going after an error, so that all the errors are reported at once.
+	// A pass can't run on a node with errors found by a previous pass:
+	//
+	// - Nodes with definition errors are neither lowered nor type checked, since the variables of
+	//   automata are looked up by the lowering, and unknown variables would be reported again
+	// - Nodes with type errors are not normalized, since the normalization relies on the types of
+	//   expressions
+	// - Nodes with normalization or clock errors are left out of the following passes
+	// - Nodes calling a node with errors are not scheduled, since the dependencies of a call
+	//   depend on the callee
+	//
+	// Until the normalization, nodes with errors are kept without their body, so that calls to them
+	// are still type checked. Errors which are not about a node, such as errors in constants and
+	// type declarations, stop the compilation after type checking, since constants are evaluated by
+	// the normalization.
+	let mut errors = Vec::new();
+
+	let mut warnings = Vec::new();
+	errors.extend(check_definitions(&f, &mut warnings).err());
+	for warning in &warnings {
+		eprintln!("{}\n", warning);
+	}
+
+	let f = lower(&without_bodies(&f, &errors), &mut errors);
+
+	errors.extend(check(&f).err());
+	if !in_nodes(&errors, &f) {
+		return collect(errors);
+	}
+
+	let nf = match normalize(&without_bodies(&f, &errors), &mut errors) {
+		Ok(nf) => nf,
+		Err(err) => {
+			errors.push(err);
+			return collect(errors);
+		},
+	};
 	eprintln!("normalized: {:?}", &nf);
 
-	let sf = sequentialize(&nf);
+	let cf = clock(&nf, &mut errors);
+	let cf = without_errors(&cf, &errors);
+	let cf = match inlined {
+		Some(selected) => inline(&cf, &selected),
+		None => cf,
+	};
+
+	let cf = fold(&cf);
+
+	let sf = match sequentialize(&cf) {
+		Ok(sf) => sf,
+		Err(err) => {
+			errors.push(err);
+			return collect(errors);
+		},
+	};
+	collect(errors)?;
 	eprintln!("sequentialized: {:?}", &sf);
+	format(&mut stdout(), &sf)
+}
+
+/// Returns the file where the nodes with errors are kept without their body.
+fn without_bodies(f: &ast::File, errors: &[CompileError]) -> ast::File {
+	ast::File{
+		nodes: f.nodes.iter().map(|n| {
+			if errors.iter().any(|err| err.is_in(&n.span)) {
+				ast::Node{body: Vec::new(), automata: Vec::new(), ..n.clone()}
+			} else {
+				n.clone()
+			}
+		}).collect(),
+		..f.clone()
+	}
+}
+
+/// Returns whether all the errors are about the source code of a node.
+fn in_nodes(errors: &[CompileError], f: &ast::File) -> bool {
+	errors.iter().all(|err| match err {
+		CompileError::Multiple(errors) => in_nodes(errors, f),
+		err => f.nodes.iter().any(|n| err.is_in(&n.span)),
+	})
+}
+
+/// Returns the file without the nodes with errors, nor the nodes calling them.
+fn without_errors(f: &nast::File, errors: &[CompileError]) -> nast::File {
+	let mut nodes: Vec<&nast::Node> = f.nodes.iter().filter(|n| !errors.iter().any(|err| err.is_in(&n.span))).collect();
+	loop {
+		let names: HashSet<&str> = nodes.iter().map(|n| n.name.as_str()).chain(f.externs.iter().map(|decl| decl.name.as_str())).collect();
+		let calls_known = |n: &&nast::Node| n.body.iter().all(|eq| match &eq.body {
+			nast::Expr::Call{name, ..} | nast::Expr::Iterate{name, ..} => names.contains(name.as_str()),
+			_ => true,
+		});
+		if nodes.iter().all(calls_known) {
+			break;
+		}
+		nodes = nodes.into_iter().filter(calls_known).collect();
+	}
+	nast::File{
+		nodes: nodes.into_iter().cloned().collect(),
+		..f.clone()
+	}
+}
 
-	format(&mut stdout(), &sf).unwrap();
+fn main() {
+	if let Err(err) = compile() {
+		eprintln!("{}", err);
+		process::exit(1);
+	}
 }
@@ -1,16 +1,29 @@
 // Normalized AST
 //
 // This is a restriction of the raw AST. Expressions are flattened into basic expressions that
-// cannot contain calls or `fby` operators. Instead they can contain `Atom::Ident` that reference
-// the result of other equations.
+// cannot contain calls or stateful operators (`fby`, `pre` and `->`). Instead they can contain
+// `Atom::Ident` that reference the result of other equations.
+//
+// Array slices are expanded into array literals of indexed items.
+//
+// Each equation is annotated with the clock on which it is computed.
+//
+// Only equations have a span: basic expressions don't, so that the passes rewriting them (folding,
+// inlining) don't have to maintain one. The span of an intermediate equation is the one of the
+// sub-expression it computes, so the diagnostics of later passes locate calls and stateful
+// operators precisely, but underline the whole equation for errors within a basic expression.
+//
+// The scheduler may split a call in two equations: one reading the outputs of the callee which
+// only depend on its memory, and one stepping the callee (see `sequentializer.rs`).
 
-use std::collections::HashMap;
-pub use crate::ast::{Type, Const, Unop, Binop};
+pub use crate::ast::{Type, Const, Unop, Binop, Iter, TypeDecl, TypeDef, ExternDecl, ExternKind, Span};
 
 #[derive(Debug, Clone)]
 pub enum Atom {
 	Ident(String),
 	Const(Const),
+	/// Reference to a constant declared at the top-level of the file
+	Global(String),
 }
 
 #[derive(Debug, Clone)]
@@ -20,29 +33,111 @@ pub enum Bexpr {
 	Binop(Binop, Box<(Bexpr, Bexpr)>),
 	If(Box<(Bexpr, Bexpr, Bexpr)>),
 	Tuple(Vec<Bexpr>),
+	When(Box<Bexpr>, String, Const),
+	Merge(String, Vec<(Const, Bexpr)>),
+	Array(Vec<Bexpr>),
+	Repeat(Box<Bexpr>, usize),
+	/// Item of an array variable
+	Index(Atom, Box<Bexpr>),
+	Record(String, Vec<(String, Bexpr)>),
+	Field(Box<Bexpr>, String),
 }
 
 #[derive(Debug, Clone)]
 pub enum Expr {
 	Bexpr(Bexpr),
+	/// Calls a node, whose memory is reset when the condition is true
 	Call{
 		name: String,
 		args: Vec<Bexpr>,
+		reset: Option<Atom>,
+	},
+	Iterate{
+		iter: Iter,
+		name: String,
+		size: usize,
+		args: Vec<Atom>,
 	},
 	Fby(Vec<Atom>, Vec<Bexpr>),
+	Pre(Vec<Bexpr>),
+	Arrow(Vec<Bexpr>, Vec<Bexpr>),
+	/// Holds the last value of an atom sampled on a sub-clock
+	Current(Atom),
+	/// Reads the outputs `outputs` of the group `group` of a node before it is stepped, from its
+	/// memory and the arguments `args` of the inputs they depend on. `call` are the variables
+	/// defined by the whole call, which name the memory of the callee.
+	Outputs{
+		name: String,
+		call: Vec<String>,
+		group: usize,
+		outputs: Vec<usize>,
+		args: Vec<Bexpr>,
+		reset: Option<Atom>,
+	},
+	/// Steps a node whose groups of outputs were read beforehand
+	Step{
+		name: String,
+		args: Vec<Bexpr>,
+		call: Vec<String>,
+	},
+}
+
+/// A clock is either the base clock of the node or a sub-clock which is only active when a clock
+/// variable is equal to a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Clock {
+	Base,
+	On(Box<Clock>, String, Const),
 }
 
 #[derive(Debug, Clone)]
 pub struct Equation {
 	pub names: Vec<String>,
 	pub body: Expr,
+	pub clock: Clock,
+	/// Span of the source code of the equation, or of the sub-expression computed by an
+	/// intermediate equation
+	pub span: Span,
+	/// Whether the equation is an intermediate created by the normalization
+	pub intermediate: bool,
+}
+
+/// Outputs of a node which depend on the same inputs, and can be read before it is stepped from
+/// its memory and these inputs only. Outputs which only depend on the memory are Moore outputs.
+#[derive(Debug, Clone)]
+pub struct OutputGroup {
+	/// Indices of the inputs
+	pub inputs: Vec<usize>,
+	/// Indices of the outputs
+	pub outputs: Vec<usize>,
+	/// Equations computing them, in order
+	pub body: Vec<Equation>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
 	pub name: String,
-	pub args_in: HashMap<String, Type>,
-	pub args_out: HashMap<String, Type>,
-	pub locals: HashMap<String, Type>,
+	pub args_in: Vec<(String, Type)>,
+	pub args_out: Vec<(String, Type)>,
+	pub locals: Vec<(String, Type)>,
 	pub body: Vec<Equation>,
+	/// Found by the scheduler
+	pub groups: Vec<OutputGroup>,
+	pub span: Span,
+}
+
+/// Constant declared at the top-level of a file, with its value.
+#[derive(Debug, Clone)]
+pub struct ConstDecl {
+	pub name: String,
+	pub typ: Type,
+	pub value: Const,
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+	pub types: Vec<TypeDecl>,
+	pub consts: Vec<ConstDecl>,
+	pub externs: Vec<ExternDecl>,
+	pub nodes: Vec<Node>,
 }
@@ -1,7 +1,8 @@
 // Normalization transforms a raw AST into a normalized AST.
 //
-// This is done by adding new intermediate equations for nested calls and `fby` operations. For
-// instance the following Lustre code which represents two nested calls to `f`:
+// This is done by adding new intermediate equations for nested calls and stateful operators
+// (`fby`, `pre` and `->`). For instance the following Lustre code which represents two nested
+// calls to `f`:
 //
 // ```lustre
 // expr = f(f(42));
@@ -31,127 +32,260 @@
 //     args: vec![Bexpr::Atom(Atom::Ident("tmp1"))],
 // };
 // ```
+//
+// Intermediates are added to the local variables of the node, with the type of the expression
+// they hold.
+//
+// Constants are evaluated, and so are the constant initial values of `fby` operators. `e1 fby e2`
+// is normalized as `e1 -> pre e2` when `e1` is not constant, since the initial value of a memory
+// must be known before the first step.
+//
+// Indexed and sliced arrays, as well as the arguments of iterators, are stored in intermediates
+// too, so that items can be accessed without computing the array again.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::ast;
 use crate::nast::*;
+use crate::diagnostic::CompileError;
+use crate::evaluator::{eval, eval_consts};
+use crate::typer::{Globals, globals, type_of_expr};
+
+/// Intermediate equations created while normalizing a node.
+struct Intermediates<'a> {
+	node: &'a ast::Node,
+	globals: &'a Globals,
+	/// Value of each constant
+	consts: &'a HashMap<String, Const>,
+	/// Names which cannot be used for new intermediates
+	names: HashSet<String>,
+	/// Name, equation, type and source span of each intermediate, in creation order
+	eqs: Vec<(String, Expr, Type, Span)>,
+}
 
-fn fresh_intermediate(intermediates: &mut HashMap<String, Option<Expr>>) -> String {
+fn fresh_intermediate(intermediates: &mut Intermediates) -> String {
 	let mut i = 1;
 	loop {
 		let name = format!("tmp{}", i);
-		if !intermediates.contains_key(&name) {
+		if !intermediates.names.contains(&name) {
 			return name;
 		}
 		i += 1;
 	}
 }
 
-fn normalize_atom(e: &ast::Expr, intermediates: &mut HashMap<String, Option<Expr>>) -> Atom {
-	match e {
-		ast::Expr::Const(c) => Atom::Const(c.clone()),
-		ast::Expr::Ident(ident) => Atom::Ident(ident.to_string()),
+fn normalize_atom(e: &ast::Expr, intermediates: &mut Intermediates) -> Result<Atom, CompileError> {
+	Ok(match &e.kind {
+		ast::ExprKind::Const(c) => Atom::Const(c.clone()),
+		ast::ExprKind::Ident(ident) => Atom::Ident(ident.to_string()),
+		ast::ExprKind::Global(name) => Atom::Global(name.to_string()),
 		_ => {
 			// Create a local variable to store the intermediate value
 			let name = fresh_intermediate(intermediates);
-			intermediates.insert(name.clone(), None); // Reserve this intermediate
-			let e = normalize_expr(e, intermediates);
-			intermediates.insert(name.clone(), Some(e));
+			intermediates.names.insert(name.clone()); // Reserve this intermediate
+			let t = type_of_expr(e, intermediates.node, intermediates.globals)?;
+			let span = &e.span;
+			let e = normalize_expr(e, intermediates)?;
+			intermediates.eqs.push((name.clone(), e, t, span.clone()));
 			Atom::Ident(name)
 		},
-	}
+	})
+}
+
+fn normalize_bexprs(exprs: &[ast::Expr], intermediates: &mut Intermediates) -> Result<Vec<Bexpr>, CompileError> {
+	exprs.iter().map(|e| normalize_bexpr(e, intermediates)).collect()
 }
 
-fn normalize_bexpr(e: &ast::Expr, intermediates: &mut HashMap<String, Option<Expr>>) -> Bexpr {
-	match e {
-		ast::Expr::Unop(unop, e) => Bexpr::Unop(unop.clone(), Box::new(normalize_bexpr(e, intermediates))),
-		ast::Expr::Binop(binop, exprs) => {
-			let (e1, e2): &(ast::Expr, ast::Expr) = &*exprs;
-			Bexpr::Binop(binop.clone(), Box::new((
-				normalize_bexpr(e1, intermediates),
-				normalize_bexpr(e2, intermediates),
+fn normalize_bexpr(e: &ast::Expr, intermediates: &mut Intermediates) -> Result<Bexpr, CompileError> {
+	Ok(match &e.kind {
+		ast::ExprKind::Unop(unop, e) => Bexpr::Unop(*unop, Box::new(normalize_bexpr(e, intermediates)?)),
+		ast::ExprKind::Binop(binop, exprs) => {
+			let (e1, e2): &(ast::Expr, ast::Expr) = exprs;
+			Bexpr::Binop(*binop, Box::new((
+				normalize_bexpr(e1, intermediates)?,
+				normalize_bexpr(e2, intermediates)?,
 			)))
 		},
-		ast::Expr::If(iff) => {
-			let (cond, body, else_part): &(ast::Expr, ast::Expr, ast::Expr) = &*iff;
+		ast::ExprKind::If(iff) => {
+			let (cond, body, else_part): &(ast::Expr, ast::Expr, ast::Expr) = iff;
 			Bexpr::If(Box::new((
-				normalize_bexpr(cond, intermediates),
-				normalize_bexpr(body, intermediates),
-				normalize_bexpr(else_part, intermediates),
+				normalize_bexpr(cond, intermediates)?,
+				normalize_bexpr(body, intermediates)?,
+				normalize_bexpr(else_part, intermediates)?,
 			)))
 		},
-		ast::Expr::Tuple(exprs) => {
-			Bexpr::Tuple(exprs.iter().map(|e| {
-				normalize_bexpr(e, intermediates)
+		ast::ExprKind::Tuple(exprs) => Bexpr::Tuple(normalize_bexprs(exprs, intermediates)?),
+		ast::ExprKind::When(e, clock, value) => {
+			Bexpr::When(Box::new(normalize_bexpr(e, intermediates)?), clock.clone(), value.clone())
+		},
+		ast::ExprKind::Merge(clock, cases) => {
+			Bexpr::Merge(clock.clone(), cases.iter().map(|(value, e)| {
+				Ok((value.clone(), normalize_bexpr(e, intermediates)?))
+			}).collect::<Result<_, CompileError>>()?)
+		},
+		ast::ExprKind::Array(exprs) => Bexpr::Array(normalize_bexprs(exprs, intermediates)?),
+		ast::ExprKind::Repeat(e, size) => Bexpr::Repeat(Box::new(normalize_bexpr(e, intermediates)?), *size),
+		ast::ExprKind::Index(index) => {
+			let (e, i): &(ast::Expr, ast::Expr) = index;
+			Bexpr::Index(normalize_atom(e, intermediates)?, Box::new(normalize_bexpr(i, intermediates)?))
+		},
+		ast::ExprKind::Slice(e, start, end) => {
+			let a = normalize_atom(e, intermediates)?;
+			Bexpr::Array((*start..=*end).map(|i| {
+				Bexpr::Index(a.clone(), Box::new(Bexpr::Atom(Atom::Const(Const::Int(i as i32)))))
 			}).collect())
 		},
-		_ => Bexpr::Atom(normalize_atom(e, intermediates)),
+		ast::ExprKind::Record(name, values) => {
+			Bexpr::Record(name.clone(), values.iter().map(|(field, e)| {
+				Ok((field.clone(), normalize_bexpr(e, intermediates)?))
+			}).collect::<Result<_, CompileError>>()?)
+		},
+		ast::ExprKind::Field(e, field) => {
+			Bexpr::Field(Box::new(normalize_bexpr(e, intermediates)?), field.clone())
+		},
+		_ => Bexpr::Atom(normalize_atom(e, intermediates)?),
+	})
+}
+
+/// Normalizes the initial value of a `fby`, which is stored in the node memory. Constant
+/// expressions are evaluated, but references to constants are kept as-is. Returns `None` if the
+/// value is not constant.
+fn normalize_init(e: &ast::Expr, intermediates: &Intermediates) -> Option<Atom> {
+	if let ast::ExprKind::Global(name) = &e.kind {
+		return Some(Atom::Global(name.clone()));
 	}
+	eval(e, intermediates.consts).ok().map(Atom::Const)
 }
 
-fn normalize_expr(e: &ast::Expr, intermediates: &mut HashMap<String, Option<Expr>>) -> Expr {
-	match e {
-		ast::Expr::Call{name, args} => Expr::Call{
+/// Normalizes `e1 fby e2` as `e1 -> pre e2`, for initial values which are not constant.
+fn normalize_fby_as_arrow(e: &ast::Expr, e1: &ast::Expr, e2: &ast::Expr, intermediates: &mut Intermediates) -> Result<Expr, CompileError> {
+	let pre = ast::Expr{kind: ast::ExprKind::Pre(Box::new(e2.clone())), span: e2.span.clone()};
+	let arrow = ast::Expr{kind: ast::ExprKind::Arrow(Box::new((e1.clone(), pre))), span: e.span.clone()};
+	normalize_expr(&arrow, intermediates)
+}
+
+fn normalize_expr(e: &ast::Expr, intermediates: &mut Intermediates) -> Result<Expr, CompileError> {
+	Ok(match &e.kind {
+		ast::ExprKind::Call{name, args, reset} => Expr::Call{
+			name: name.to_string(),
+			args: normalize_bexprs(args, intermediates)?,
+			reset: match reset {
+				Some(cond) => Some(normalize_atom(cond, intermediates)?),
+				None => None,
+			},
+		},
+		ast::ExprKind::Iterate{iter, name, size, args} => Expr::Iterate{
+			iter: *iter,
 			name: name.to_string(),
-			args: args.iter().map(|e| normalize_bexpr(e, intermediates)).collect(),
+			size: *size,
+			args: args.iter().map(|e| normalize_atom(e, intermediates)).collect::<Result<_, _>>()?,
+		},
+		ast::ExprKind::Fby(fby) => {
+			let (e1, e2): &(ast::Expr, ast::Expr) = fby;
+			let init = match &e1.kind {
+				ast::ExprKind::Tuple(v1) => v1.iter().map(|e| normalize_init(e, intermediates)).collect(),
+				_ => normalize_init(e1, intermediates).map(|init| vec![init]),
+			};
+			let init = match init {
+				Some(init) => init,
+				None => return normalize_fby_as_arrow(e, e1, e2, intermediates),
+			};
+			let next = match (&e1.kind, &e2.kind) {
+				(ast::ExprKind::Tuple(_), ast::ExprKind::Tuple(v2)) => normalize_bexprs(v2, intermediates)?,
+				// The next value is kept as a tuple if only one side is a tuple literal, the memory
+				// holds the whole tuple either way
+				_ => vec![normalize_bexpr(e2, intermediates)?],
+			};
+			Expr::Fby(init, next)
+		},
+		ast::ExprKind::Pre(e) => {
+			match &e.kind {
+				ast::ExprKind::Tuple(v) => Expr::Pre(normalize_bexprs(v, intermediates)?),
+				_ => Expr::Pre(vec![normalize_bexpr(e, intermediates)?]),
+			}
 		},
-		ast::Expr::Fby(fby) => {
-			let (e1, e2): &(ast::Expr, ast::Expr) = &*fby;
-			match (e1, e2) {
-				(ast::Expr::Tuple(v1), ast::Expr::Tuple(v2)) => {
-					Expr::Fby(
-						v1.iter().map(|e| normalize_atom(e, intermediates)).collect(),
-						v2.iter().map(|e| normalize_bexpr(e, intermediates)).collect(),
+		ast::ExprKind::Arrow(arrow) => {
+			let (e1, e2): &(ast::Expr, ast::Expr) = arrow;
+			match (&e1.kind, &e2.kind) {
+				(ast::ExprKind::Tuple(v1), ast::ExprKind::Tuple(v2)) => {
+					Expr::Arrow(
+						normalize_bexprs(v1, intermediates)?,
+						normalize_bexprs(v2, intermediates)?,
 					)
 				},
-				(ast::Expr::Tuple(_), _) | (_, ast::Expr::Tuple(_)) => {
-					panic!("Mismatched tuples in fby")
-				},
 				_ => {
-					Expr::Fby(
-						vec![normalize_atom(e1, intermediates)],
-						vec![normalize_bexpr(e2, intermediates)],
+					// Tuples are left as-is if only one side is a tuple literal
+					Expr::Arrow(
+						vec![normalize_bexpr(e1, intermediates)?],
+						vec![normalize_bexpr(e2, intermediates)?],
 					)
 				},
 			}
 		},
-		_ => Expr::Bexpr(normalize_bexpr(e, intermediates)),
-	}
+		ast::ExprKind::Current(e) => Expr::Current(normalize_atom(e, intermediates)?),
+		_ => Expr::Bexpr(normalize_bexpr(e, intermediates)?),
+	})
 }
 
-fn normalize_equation(eq: &ast::Equation, intermediates: &mut HashMap<String, Option<Expr>>) -> Equation {
-	Equation{
+fn normalize_equation(eq: &ast::Equation, intermediates: &mut Intermediates) -> Result<Equation, CompileError> {
+	Ok(Equation{
 		names: eq.names.clone(),
-		body: normalize_expr(&eq.body, intermediates),
-	}
+		body: normalize_expr(&eq.body, intermediates)?,
+		clock: Clock::Base, // Inferred later on
+		span: eq.span.clone(),
+		intermediate: false,
+	})
 }
 
-fn normalize_node(n: &ast::Node) -> Node {
-	let mut intermediates = HashMap::new();
-	// Prevent local names from being used for intermediates
-	for (name, _) in n.locals.iter() {
-		intermediates.insert(name.clone(), None);
+fn normalize_node(n: &ast::Node, globals: &Globals, consts: &HashMap<String, Const>) -> Result<Node, CompileError> {
+	let mut intermediates = Intermediates{
+		node: n,
+		globals,
+		consts,
+		names: HashSet::new(),
+		eqs: Vec::new(),
+	};
+	// Prevent variable names from being used for intermediates
+	for (name, _) in n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter()) {
+		intermediates.names.insert(name.clone());
 	}
 	let mut body: Vec<Equation> = n.body.iter().map(|eq| {
 		normalize_equation(eq, &mut intermediates)
-	}).collect();
+	}).collect::<Result<_, _>>()?;
 	let mut locals = n.locals.clone();
-	for (name, e) in intermediates {
-		if let Some(e) = e {
-			// TODO: the local name isn't Type::Unit (though we don't use it)
-			locals.insert(name.clone(), Type::Unit);
-			body.push(Equation{names: vec!(name), body: e});
-		}
+	for (name, e, t, span) in intermediates.eqs {
+		locals.push((name.clone(), t));
+		body.push(Equation{names: vec!(name), body: e, clock: Clock::Base, span, intermediate: true});
 	}
-	Node{
+	Ok(Node{
 		name: n.name.clone(),
 		args_in: n.args_in.clone(),
 		args_out: n.args_out.clone(),
-		locals: locals,
-		body: body,
-	}
+		locals,
+		body,
+		groups: Vec::new(), // Found later on
+		span: n.span.clone(),
+	})
 }
 
-pub fn normalize(f: &[ast::Node]) -> Vec<Node> {
-	f.iter().map(normalize_node).collect()
+/// Normalizes each node. Errors about a node are added to `errors`, and the node is left out.
+/// Errors about constants are returned.
+pub fn normalize(f: &ast::File, errors: &mut Vec<CompileError>) -> Result<File, CompileError> {
+	let globals = globals(f)?;
+	let values = eval_consts(&f.consts)?;
+	let consts = f.consts.iter().zip(values.iter()).map(|(decl, (_, value))| {
+		ConstDecl{name: decl.name.clone(), typ: decl.typ.clone(), value: value.clone()}
+	}).collect();
+	let values = values.into_iter().collect();
+	Ok(File{
+		types: f.types.clone(),
+		consts,
+		externs: f.externs.clone(),
+		nodes: f.nodes.iter().filter_map(|n| match normalize_node(n, &globals, &values) {
+			Ok(n) => Some(n),
+			Err(err) => {
+				errors.push(err);
+				None
+			},
+		}).collect(),
+	})
 }
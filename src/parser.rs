@@ -1,67 +1,186 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::iter::Peekable;
+use std::rc::Rc;
 use pest::Parser;
-use pest::iterators::Pair;
-use pest::error::Error;
+use pest::iterators::{Pair, Pairs};
+use pest::error::{Error, InputLocation};
 use crate::ast::*;
+use crate::diagnostic::{CompileError, Diagnostic, Source, error};
+use crate::evaluator::{eval, eval_consts};
+use crate::typer::{format_type, type_of_const};
 
 #[derive(Parser)]
 #[grammar = "lustre.pest"]
 pub struct LustreParser;
 
-fn parse_type(pair: Pair<Rule>) -> Type {
-	match pair.as_str() {
+/// Maximum number of nested instances of generic nodes, which prevents endless instantiations.
+const MAX_INSTANCE_DEPTH: usize = 64;
+
+/// Instance of a generic node requested by a call.
+struct Instance {
+	generic: String,
+	values: Vec<Const>,
+	name: String,
+	/// Number of instances this one is nested in
+	depth: usize,
+}
+
+/// Declarations visible from the item being parsed.
+struct Scope {
+	types: Vec<TypeDecl>,
+	consts: Vec<ConstDecl>,
+	generics: Vec<GenericNode>,
+	/// Value of each static parameter, when parsing an instance of a generic node
+	statics: Vec<(String, Const)>,
+	/// Instances requested by the calls parsed so far
+	instances: RefCell<Vec<Instance>>,
+	/// Depth of the instance being parsed, if any
+	depth: usize,
+	/// Parsed source code, and offset of the parsed text in it
+	source: Rc<Source>,
+	offset: usize,
+}
+
+/// File loaded by another file.
+#[derive(Debug)]
+pub enum Import {
+	/// Path of a file, relative to the including file
+	Include(String),
+	/// Name of a package, declared in a file of the same name next to the including file
+	Uses(String),
+}
+
+/// Declarations at the beginning of a file, which must be resolved before parsing it.
+#[derive(Debug)]
+pub struct Header {
+	pub package: Option<String>,
+	pub imports: Vec<Import>,
+}
+
+/// Returns the span of a pair in the source code.
+fn span(pair: &Pair<Rule>, scope: &Scope) -> Span {
+	let span = pair.as_span();
+	Span::new(&scope.source, scope.offset + span.start(), scope.offset + span.end())
+}
+
+/// Converts an error of the grammar into a diagnostic. `offset` is the offset of the parsed text
+/// in the source code.
+fn syntax_error(err: Error<Rule>, source: &Rc<Source>, offset: usize) -> CompileError {
+	let (start, end) = match err.location {
+		InputLocation::Pos(pos) => (pos, pos),
+		InputLocation::Span(span) => span,
+	};
+	let span = Span::new(source, offset + start, offset + end);
+	CompileError::Parse(Diagnostic::new(&span, err.variant.message().to_string()))
+}
+
+/// Evaluates a static expression, which can only refer to constants and static parameters.
+fn eval_static(e: &Expr, scope: &Scope) -> Result<Const, CompileError> {
+	let consts: HashMap<String, Const> = eval_consts(&scope.consts)?.into_iter().collect();
+	match eval(e, &consts) {
+		Ok(c) => Ok(c),
+		Err(msg) => error(CompileError::Type, &e.span, format!("invalid static expression: {}", msg)),
+	}
+}
+
+fn parse_size(pair: Pair<Rule>, scope: &Scope) -> Result<usize, CompileError> {
+	let pair = match pair.as_rule() {
+		Rule::size => pair.into_inner().next().unwrap(),
+		_ => pair,
+	};
+	match pair.as_rule() {
+		Rule::int => match pair.as_str().parse::<usize>() {
+			Ok(size) => Ok(size),
+			Err(_) => error(CompileError::Parse, &span(&pair, scope), format!("size `{}` is out of range", pair.as_str())),
+		},
+		Rule::ident => {
+			let e = parse_ident(&pair, scope);
+			if let ExprKind::Ident(name) = &e.kind {
+				return error(CompileError::Type, &e.span, format!("size `{}` is not a constant", name));
+			}
+			match eval_static(&e, scope)? {
+				Const::Int(i) if i >= 0 => Ok(i as usize),
+				c => error(CompileError::Type, &e.span, format!("size `{}` is not a positive integer: {:?}", pair.as_str(), c)),
+			}
+		},
+		_ => unreachable!(),
+	}
+}
+
+/// Parses a type. Type names refer to builtin types or to the types declared in the scope.
+fn parse_type(pair: Pair<Rule>, scope: &Scope) -> Result<Type, CompileError> {
+	assert!(pair.as_rule() == Rule::typ);
+	let mut inner_rules = pair.into_inner();
+	let ident = inner_rules.next().unwrap();
+	let mut t = match ident.as_str() {
 		"unit" => Type::Unit,
 		"bool" => Type::Bool,
 		"int" => Type::Int,
 		"float" => Type::Float,
 		"string" => Type::String,
-		_ => panic!("invalid type: {}", pair.as_str())
+		name => match scope.types.iter().find(|decl| decl.name == name) {
+			Some(decl) => match decl.def {
+				TypeDef::Record(_) => Type::Record(name.to_string()),
+				TypeDef::Enum(_) => Type::Enum(name.to_string()),
+			},
+			None => return error(CompileError::UnknownName, &span(&ident, scope), format!("unknown type `{}`", name)),
+		},
+	};
+	for size in inner_rules {
+		t = Type::Array(Box::new(t), parse_size(size, scope)?);
 	}
+	Ok(t)
 }
 
-fn parse_arg(pair: Pair<Rule>) -> (Vec<String>, Type) {
+fn parse_arg(pair: Pair<Rule>, scope: &Scope) -> Result<(Vec<String>, Type), CompileError> {
 	assert!(pair.as_rule() == Rule::arg);
 
 	let mut inner_rules = pair.into_inner();
-	(
+	Ok((
 		inner_rules.next().unwrap().into_inner().map(|p| p.as_str().to_string()).collect(),
-		parse_type(inner_rules.next().unwrap()),
-	)
+		parse_type(inner_rules.next().unwrap(), scope)?,
+	))
 }
 
-fn parse_arg_list(pair: Pair<Rule>) -> HashMap<String, Type> {
-	assert!(pair.as_rule() == Rule::arg_list);
-	let mut arg_list = HashMap::new();
+fn parse_arg_list(pair: Pair<Rule>, scope: &Scope) -> Result<Vec<(String, Type)>, CompileError> {
+	assert!(pair.as_rule() == Rule::arg_list || pair.as_rule() == Rule::field_list);
+	let mut arg_list = Vec::new();
 	for arg_idents in pair.into_inner() {
-		let (args, typ) = parse_arg(arg_idents);
+		let (args, typ) = parse_arg(arg_idents, scope)?;
 		for arg in args {
-			arg_list.insert(arg, typ.clone());
+			arg_list.push((arg, typ.clone()));
 		}
 	}
-	arg_list
+	Ok(arg_list)
 }
 
-fn parse_constant(pair: Pair<Rule>) -> Const {
-	match pair.as_rule() {
+fn parse_constant(pair: Pair<Rule>, scope: &Scope) -> Result<Const, CompileError> {
+	Ok(match pair.as_rule() {
 		Rule::bool => match pair.as_str() {
 			"true" => Const::Bool(true),
 			"false" => Const::Bool(false),
 			_ => unreachable!(),
 		},
-		Rule::int => Const::Int(pair.as_str().parse::<i32>().unwrap()),
+		Rule::int => match pair.as_str().parse::<i32>() {
+			Ok(i) => Const::Int(i),
+			Err(_) => return error(CompileError::Parse, &span(&pair, scope), format!("integer literal `{}` is out of range", pair.as_str())),
+		},
+		// Floats which are too large are parsed as infinity, like Rust does
 		Rule::float => Const::Float(pair.as_str().parse::<f32>().unwrap()),
 		Rule::string => Const::String(pair.into_inner().next().unwrap().as_str().to_string()),
 		Rule::unit => Const::Unit,
 		_ => unreachable!(),
-	}
+	})
 }
 
-fn parse_local(pair: Pair<Rule>) -> HashMap<String, Type> {
+fn parse_local(pair: Pair<Rule>, scope: &Scope) -> Result<Vec<(String, Type)>, CompileError> {
 	assert!(pair.as_rule() == Rule::local);
-	match pair.into_inner().next() {
-		Some(v) => parse_arg_list(v),
-		None => HashMap::new(),
+	let mut locals = Vec::new();
+	for arg_list in pair.into_inner() {
+		locals.extend(parse_arg_list(arg_list, scope)?);
 	}
+	Ok(locals)
 }
 
 fn parse_unop(pair: Pair<Rule>) -> Unop {
@@ -77,6 +196,54 @@ fn parse_unop(pair: Pair<Rule>) -> Unop {
 enum BinopOrFby {
 	Binop(Binop),
 	Fby,
+	Arrow,
+}
+
+#[derive(PartialEq)]
+enum Assoc {
+	Left,
+	Right,
+	None,
+}
+
+/// Returns the precedence and the associativity of a binary operator. Operators with a higher
+/// precedence bind tighter:
+///
+/// | Operators                   | Associativity |
+/// |-----------------------------|---------------|
+/// | `->`                        | right         |
+/// | `fby`                       | right         |
+/// | `or`, `xor`                 | left          |
+/// | `and`                       | left          |
+/// | `<`, `<=`, `=`, `<>`, ...   | none          |
+/// | `not`                       | prefix        |
+/// | `+`, `-`, `+.`, `-.`        | left          |
+/// | `*`, `/`, `*.`, `/.`        | left          |
+/// | `-`, `-.`                   | prefix        |
+///
+/// `fby` binds looser than the other operators but `->`, so that `0 fby n + 1` is `0 fby (n + 1)`.
+fn binop_precedence(op: &BinopOrFby) -> (u8, Assoc) {
+	match op {
+		BinopOrFby::Arrow => (1, Assoc::Right),
+		BinopOrFby::Fby => (2, Assoc::Right),
+		BinopOrFby::Binop(Binop::Or) | BinopOrFby::Binop(Binop::Xor) => (3, Assoc::Left),
+		BinopOrFby::Binop(Binop::And) => (4, Assoc::Left),
+		BinopOrFby::Binop(Binop::Lt) | BinopOrFby::Binop(Binop::Gt) | BinopOrFby::Binop(Binop::Leq) |
+		BinopOrFby::Binop(Binop::Geq) | BinopOrFby::Binop(Binop::Eq) | BinopOrFby::Binop(Binop::Neq) => {
+			(5, Assoc::None)
+		},
+		BinopOrFby::Binop(Binop::Plus) | BinopOrFby::Binop(Binop::Minus) |
+		BinopOrFby::Binop(Binop::PlusDot) | BinopOrFby::Binop(Binop::MinusDot) => (7, Assoc::Left),
+		BinopOrFby::Binop(Binop::Mult) | BinopOrFby::Binop(Binop::Div) |
+		BinopOrFby::Binop(Binop::MultDot) | BinopOrFby::Binop(Binop::DivDot) => (8, Assoc::Left),
+	}
+}
+
+fn unop_precedence(op: Unop) -> u8 {
+	match op {
+		Unop::Not => 6,
+		Unop::Minus | Unop::MinusDot => 9,
+	}
 }
 
 fn parse_binop_or_fby(pair: Pair<Rule>) -> BinopOrFby {
@@ -95,105 +262,645 @@ fn parse_binop_or_fby(pair: Pair<Rule>) -> BinopOrFby {
 		"<=" => BinopOrFby::Binop(Binop::Leq),
 		">=" => BinopOrFby::Binop(Binop::Geq),
 		"=" => BinopOrFby::Binop(Binop::Eq),
+		"<>" => BinopOrFby::Binop(Binop::Neq),
 		"and" => BinopOrFby::Binop(Binop::And),
 		"or" => BinopOrFby::Binop(Binop::Or),
+		"xor" => BinopOrFby::Binop(Binop::Xor),
 		"fby" => BinopOrFby::Fby,
+		"->" => BinopOrFby::Arrow,
 		_ => unreachable!(),
 	}
 }
 
-fn parse_term(pair: Pair<Rule>) -> Expr {
-	match pair.as_rule() {
-		Rule::call => {
+/// Returns the enumeration value with the given name, if any.
+fn find_variant(name: &str, scope: &Scope) -> Option<Const> {
+	scope.types.iter().find_map(|decl| match &decl.def {
+		TypeDef::Enum(variants) if variants.iter().any(|v| v == name) => {
+			Some(Const::Enum(decl.name.clone(), name.to_string()))
+		},
+		_ => None,
+	})
+}
+
+fn parse_variant(pair: Pair<Rule>, scope: &Scope) -> Result<Const, CompileError> {
+	assert!(pair.as_rule() == Rule::ident);
+	match find_variant(pair.as_str(), scope) {
+		Some(c) => Ok(c),
+		None => error(CompileError::UnknownName, &span(&pair, scope), format!("unknown enum value `{}`", pair.as_str())),
+	}
+}
+
+fn parse_clock_cond(pair: Pair<Rule>, scope: &Scope) -> Result<(String, Const), CompileError> {
+	assert!(pair.as_rule() == Rule::clock_cond);
+	let inner = pair.into_inner().next().unwrap();
+	Ok(match inner.as_rule() {
+		Rule::ident => (inner.as_str().to_string(), Const::Bool(true)),
+		Rule::clock_not => {
+			let ident = inner.into_inner().next().unwrap();
+			(ident.as_str().to_string(), Const::Bool(false))
+		},
+		Rule::clock_enum => {
+			let mut inner_rules = inner.into_inner();
+			let value = parse_variant(inner_rules.next().unwrap(), scope)?;
+			(inner_rules.next().unwrap().as_str().to_string(), value)
+		},
+		_ => unreachable!(),
+	})
+}
+
+/// Parses the i-th case of a `merge`. Cases without a value are `true` then `false`, other cases
+/// start with a boolean or enumeration value.
+fn parse_merge_case(pair: Pair<Rule>, i: usize, scope: &Scope) -> Result<(Const, Expr), CompileError> {
+	assert!(pair.as_rule() == Rule::merge_case);
+	let case_span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	let first = inner_rules.next().unwrap();
+	match inner_rules.next() {
+		Some(e) => {
+			let value = match first.as_rule() {
+				Rule::bool => parse_constant(first, scope)?,
+				_ => parse_variant(first, scope)?,
+			};
+			Ok((value, parse_expr(e, scope)?))
+		},
+		None => {
+			let value = match i {
+				0 => true,
+				1 => false,
+				_ => return error(CompileError::Parse, &case_span, "merge expects two cases without values".to_string()),
+			};
+			Ok((Const::Bool(value), parse_expr(first, scope)?))
+		},
+	}
+}
+
+/// Resolves an identifier to an enumeration value, a static parameter, a constant or a variable.
+fn parse_ident(pair: &Pair<Rule>, scope: &Scope) -> Expr {
+	let id = pair.as_str();
+	let kind = if let Some(c) = find_variant(id, scope) {
+		ExprKind::Const(c)
+	} else if let Some((_, c)) = scope.statics.iter().find(|(name, _)| name == id) {
+		ExprKind::Const(c.clone())
+	} else if scope.consts.iter().any(|decl| decl.name == id) {
+		ExprKind::Global(id.to_string())
+	} else {
+		ExprKind::Ident(id.to_string())
+	};
+	Expr{kind, span: span(pair, scope)}
+}
+
+/// Part of the name of an instance, with `_` doubled so that different instances get different
+/// names.
+fn escape(part: &str) -> String {
+	part.replace('_', "__")
+}
+
+/// Name of the instance of a generic node for a static argument.
+fn format_static(c: &Const) -> String {
+	match c {
+		Const::Bool(b) => b.to_string(),
+		Const::Int(i) if *i < 0 => format!("m{}", -(*i as i64)),
+		Const::Int(i) => i.to_string(),
+		Const::Float(f) => format!("{:?}", f).replace('-', "m").replace('.', "_"),
+		Const::Enum(_, variant) => variant.clone(),
+		_ => unreachable!(), // Rejected by parse_static_param
+	}
+}
+
+/// Requests the instance of the generic node `name` with the static arguments `values`, and
+/// returns the name of the instance. `span` is the span of the call.
+fn instantiate(name: &str, values: Vec<Const>, span: &Span, scope: &Scope) -> Result<String, CompileError> {
+	let generic = match scope.generics.iter().find(|g| g.name == name) {
+		Some(generic) => generic,
+		None => return error(CompileError::UnknownName, span, format!("unknown generic node `{}`", name)),
+	};
+	if generic.statics.len() != values.len() {
+		return error(CompileError::Arity, span, format!("generic node `{}` expects {} static arguments, got {}", name, generic.statics.len(), values.len()));
+	}
+	for ((param, t), c) in generic.statics.iter().zip(values.iter()) {
+		if type_of_const(c) != *t {
+			return error(CompileError::Type, span, format!("static argument `{}` of `{}` should have type {}, got {}", param, name, format_type(t), format_type(&type_of_const(c))));
+		}
+	}
+	// Identifiers can't start with `_`, so instances can't have the name of another node
+	let mut instance_name = format!("_{}", escape(name));
+	for c in &values {
+		instance_name.push('_');
+		instance_name.push_str(&escape(&format_static(c)));
+	}
+	if scope.depth >= MAX_INSTANCE_DEPTH {
+		return error(CompileError::Definition, span, format!("too many nested instances of generic nodes, when instantiating `{}`", &instance_name));
+	}
+	scope.instances.borrow_mut().push(Instance{
+		generic: name.to_string(),
+		values,
+		name: instance_name.clone(),
+		depth: scope.depth + 1,
+	});
+	Ok(instance_name)
+}
+
+fn parse_iterator(pair: Pair<Rule>) -> Iter {
+	assert!(pair.as_rule() == Rule::iterator);
+	match pair.as_str() {
+		"map" => Iter::Map,
+		"fold" => Iter::Fold,
+		"red" => Iter::Red,
+		_ => unreachable!(),
+	}
+}
+
+fn parse_term(pair: Pair<Rule>, scope: &Scope) -> Result<Expr, CompileError> {
+	let span = span(&pair, scope);
+	let kind = match pair.as_rule() {
+		Rule::iterate => {
 			let mut inner_rules = pair.into_inner();
-			Expr::Call{
+			ExprKind::Iterate{
+				iter: parse_iterator(inner_rules.next().unwrap()),
 				name: inner_rules.next().unwrap().as_str().to_string(),
-				args: inner_rules.map(parse_expr).collect(),
+				size: parse_size(inner_rules.next().unwrap(), scope)?,
+				args: inner_rules.map(|p| parse_expr(p, scope)).collect::<Result<_, _>>()?,
 			}
 		},
-		Rule::constant => {
-			let c = parse_constant(pair.into_inner().next().unwrap());
-			Expr::Const(c)
+		Rule::call => {
+			let mut inner_rules = pair.into_inner().peekable();
+			let mut name = inner_rules.next().unwrap().as_str().to_string();
+			if inner_rules.peek().map(|p| p.as_rule()) == Some(Rule::static_args) {
+				let values = inner_rules.next().unwrap().into_inner().map(|p| {
+					eval_static(&parse_expr(p, scope)?, scope)
+				}).collect::<Result<_, _>>()?;
+				name = instantiate(&name, values, &span, scope)?;
+			}
+			ExprKind::Call{
+				name,
+				args: inner_rules.map(|p| parse_expr(p, scope)).collect::<Result<_, _>>()?,
+				reset: None,
+			}
 		},
-		Rule::unop_expr => {
+		Rule::reset_call => {
 			let mut inner_rules = pair.into_inner();
-			let op = parse_unop(inner_rules.next().unwrap());
-			let e = parse_expr(inner_rules.next().unwrap());
-			Expr::Unop(op, Box::new(e))
+			let call = parse_term(inner_rules.next().unwrap(), scope)?;
+			let cond = parse_expr(inner_rules.next().unwrap(), scope)?;
+			match call.kind {
+				ExprKind::Call{name, args, ..} => ExprKind::Call{name, args, reset: Some(Box::new(cond))},
+				_ => unreachable!(),
+			}
+		},
+		Rule::constant => {
+			let c = parse_constant(pair.into_inner().next().unwrap(), scope)?;
+			ExprKind::Const(c)
 		},
 		Rule::ifrule => {
 			let mut inner_rules = pair.into_inner();
-			let cond = parse_expr(inner_rules.next().unwrap());
-			let bif = parse_expr(inner_rules.next().unwrap());
-			let belse = parse_expr(inner_rules.next().unwrap());
-			Expr::If(Box::new((cond, bif, belse)))
+			let cond = parse_expr(inner_rules.next().unwrap(), scope)?;
+			let bif = parse_expr(inner_rules.next().unwrap(), scope)?;
+			let belse = parse_expr(inner_rules.next().unwrap(), scope)?;
+			ExprKind::If(Box::new((cond, bif, belse)))
 		},
-		Rule::ident => {
-			let id = pair.as_str().to_string();
-			Expr::Ident(id)
+		Rule::ident => return Ok(parse_ident(&pair, scope)),
+		Rule::pre_expr => {
+			let e = parse_term(pair.into_inner().next().unwrap(), scope)?;
+			ExprKind::Pre(Box::new(e))
+		},
+		Rule::current_expr => {
+			let e = parse_term(pair.into_inner().next().unwrap(), scope)?;
+			ExprKind::Current(Box::new(e))
+		},
+		Rule::merge_expr => {
+			let mut inner_rules = pair.into_inner();
+			let clock = inner_rules.next().unwrap().as_str().to_string();
+			let cases = inner_rules.enumerate().map(|(i, p)| parse_merge_case(p, i, scope)).collect::<Result<_, _>>()?;
+			ExprKind::Merge(clock, cases)
+		},
+		Rule::postfixed => {
+			let mut inner_rules = pair.into_inner();
+			let mut e = parse_term(inner_rules.next().unwrap(), scope)?;
+			for postfix in inner_rules {
+				let span = e.span.to(&self::span(&postfix, scope));
+				let kind = match postfix.as_rule() {
+					Rule::index => {
+						let i = parse_expr(postfix.into_inner().next().unwrap(), scope)?;
+						ExprKind::Index(Box::new((e, i)))
+					},
+					Rule::slice => {
+						let mut bounds = postfix.into_inner();
+						let start = parse_size(bounds.next().unwrap(), scope)?;
+						let end = parse_size(bounds.next().unwrap(), scope)?;
+						ExprKind::Slice(Box::new(e), start, end)
+					},
+					Rule::repeat => {
+						let size = parse_size(postfix.into_inner().next().unwrap(), scope)?;
+						ExprKind::Repeat(Box::new(e), size)
+					},
+					Rule::field => {
+						let field = postfix.into_inner().next().unwrap().as_str().to_string();
+						ExprKind::Field(Box::new(e), field)
+					},
+					_ => unreachable!(),
+				};
+				e = Expr{kind, span};
+			}
+			return Ok(e);
+		},
+		Rule::sampled => {
+			let mut inner_rules = pair.into_inner();
+			let mut e = parse_term(inner_rules.next().unwrap(), scope)?;
+			for clock_cond in inner_rules {
+				let span = e.span.to(&self::span(&clock_cond, scope));
+				let (clock, value) = parse_clock_cond(clock_cond, scope)?;
+				e = Expr{kind: ExprKind::When(Box::new(e), clock, value), span};
+			}
+			return Ok(e);
 		},
 		Rule::pexpr => {
-			parse_expr(pair.into_inner().next().unwrap())
+			return parse_expr(pair.into_inner().next().unwrap(), scope);
 		},
 		Rule::expr_tuple => {
-			let exprs = pair.into_inner().map(parse_expr).collect();
-			Expr::Tuple(exprs)
+			let exprs = pair.into_inner().map(|p| parse_expr(p, scope)).collect::<Result<_, _>>()?;
+			ExprKind::Tuple(exprs)
+		},
+		Rule::record => {
+			let mut inner_rules = pair.into_inner();
+			let name = inner_rules.next().unwrap().as_str().to_string();
+			let fields = inner_rules.map(|field_value| {
+				let mut inner_rules = field_value.into_inner();
+				let field = inner_rules.next().unwrap().as_str().to_string();
+				Ok((field, parse_expr(inner_rules.next().unwrap(), scope)?))
+			}).collect::<Result<_, CompileError>>()?;
+			ExprKind::Record(name, fields)
+		},
+		Rule::array => {
+			let exprs = pair.into_inner().map(|p| parse_expr(p, scope)).collect::<Result<_, _>>()?;
+			ExprKind::Array(exprs)
 		},
 		_ => unreachable!(),
-	}
+	};
+	Ok(Expr{kind, span})
 }
 
-fn parse_expr(pair: Pair<Rule>) -> Expr {
-	assert!(pair.as_rule() == Rule::expr);
-	let mut inner_rules = pair.into_inner();
-	let left = parse_term(inner_rules.next().unwrap());
-	match inner_rules.next() {
-		Some(binop_pair) => {
-			let binop_or_fby = parse_binop_or_fby(binop_pair);
-			let right = parse_expr(inner_rules.next().unwrap());
-			match binop_or_fby {
-				BinopOrFby::Binop(binop) => Expr::Binop(binop, Box::new((left, right))),
-				BinopOrFby::Fby => Expr::Fby(Box::new((left, right))),
-			}
+/// Parses an operand, preceded by its unary operators.
+fn parse_operand(pairs: &mut Peekable<Pairs<Rule>>, scope: &Scope) -> Result<Expr, CompileError> {
+	let pair = pairs.next().unwrap();
+	match pair.as_rule() {
+		Rule::unop => {
+			let span = span(&pair, scope);
+			let op = parse_unop(pair);
+			let e = parse_binops(pairs, unop_precedence(op), scope)?;
+			Ok(Expr{span: span.to(&e.span), kind: ExprKind::Unop(op, Box::new(e))})
 		},
-		None => left,
+		_ => parse_term(pair, scope),
 	}
 }
 
-fn parse_eq(pair: Pair<Rule>) -> Equation {
+/// Parses a sequence of binary operators whose precedence is at least `min`, by precedence
+/// climbing.
+fn parse_binops(pairs: &mut Peekable<Pairs<Rule>>, min: u8, scope: &Scope) -> Result<Expr, CompileError> {
+	let mut left = parse_operand(pairs, scope)?;
+	let mut prev = None;
+	while let Some(pair) = pairs.peek() {
+		let op = parse_binop_or_fby(pair.clone());
+		let (prec, assoc) = binop_precedence(&op);
+		if prec < min {
+			break;
+		}
+		if prev == Some(prec) && assoc == Assoc::None {
+			return error(CompileError::Parse, &span(pair, scope), format!("operator `{}` is not associative, parentheses are needed", pair.as_str()));
+		}
+		pairs.next();
+		let right = match assoc {
+			Assoc::Right => parse_binops(pairs, prec, scope)?,
+			Assoc::Left | Assoc::None => parse_binops(pairs, prec + 1, scope)?,
+		};
+		let span = left.span.to(&right.span);
+		let kind = match op {
+			BinopOrFby::Binop(binop) => ExprKind::Binop(binop, Box::new((left, right))),
+			BinopOrFby::Fby => ExprKind::Fby(Box::new((left, right))),
+			BinopOrFby::Arrow => ExprKind::Arrow(Box::new((left, right))),
+		};
+		left = Expr{kind, span};
+		prev = Some(prec);
+	}
+	Ok(left)
+}
+
+fn parse_expr(pair: Pair<Rule>, scope: &Scope) -> Result<Expr, CompileError> {
+	assert!(pair.as_rule() == Rule::expr);
+	parse_binops(&mut pair.into_inner().peekable(), 0, scope)
+}
+
+fn parse_eq(pair: Pair<Rule>, scope: &Scope) -> Result<Equation, CompileError> {
 	assert!(pair.as_rule() == Rule::eq);
+	let span = span(&pair, scope);
 	let mut inner_rules = pair.into_inner();
-	Equation{
+	Ok(Equation{
 		names: inner_rules.next().unwrap().into_inner().map(|p| p.as_str().to_string()).collect(),
-		body: parse_expr(inner_rules.next().unwrap()),
-	}
+		body: parse_expr(inner_rules.next().unwrap(), scope)?,
+		span,
+	})
 }
 
-fn parse_eq_list(pair: Pair<Rule>) -> Vec<Equation> {
+fn parse_transitions(pair: Pair<Rule>, scope: &Scope) -> Result<Vec<Transition>, CompileError> {
+	pair.into_inner().map(|transition| {
+		let span = span(&transition, scope);
+		let mut inner_rules = transition.into_inner();
+		Ok(Transition{
+			cond: parse_expr(inner_rules.next().unwrap(), scope)?,
+			restart: inner_rules.next().unwrap().as_str() == "then",
+			target: inner_rules.next().unwrap().as_str().to_string(),
+			span,
+		})
+	}).collect()
+}
+
+fn parse_state(pair: Pair<Rule>, scope: &Scope) -> Result<State, CompileError> {
+	assert!(pair.as_rule() == Rule::state);
+
+	let span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	let name = inner_rules.next().unwrap().as_str().to_string();
+	let locals = parse_local(inner_rules.next().unwrap(), scope)?;
+	let unless = parse_transitions(inner_rules.next().unwrap(), scope)?;
+	let (body, automata) = parse_eq_list(inner_rules.next().unwrap(), scope)?;
+	let until = parse_transitions(inner_rules.next().unwrap(), scope)?;
+	Ok(State{name, locals, unless, body, automata, until, span})
+}
+
+fn parse_eq_list(pair: Pair<Rule>, scope: &Scope) -> Result<(Vec<Equation>, Vec<Automaton>), CompileError> {
 	assert!(pair.as_rule() == Rule::eq_list);
-	pair.into_inner().map(parse_eq).collect()
+	let mut eqs = Vec::new();
+	let mut automata = Vec::new();
+	for p in pair.into_inner() {
+		match p.as_rule() {
+			Rule::eq => eqs.push(parse_eq(p, scope)?),
+			Rule::automaton => automata.push(Automaton{
+				span: span(&p, scope),
+				states: p.into_inner().map(|state| parse_state(state, scope)).collect::<Result<_, _>>()?,
+			}),
+			_ => unreachable!(),
+		}
+	}
+	Ok((eqs, automata))
 }
 
-fn parse_node(pair: Pair<Rule>) -> Node {
+fn parse_node(pair: Pair<Rule>, scope: &Scope) -> Result<Node, CompileError> {
 	assert!(pair.as_rule() == Rule::node);
 
+	let span = span(&pair, scope);
 	let mut inner_rules = pair.into_inner();
-	Node{
+	let name = inner_rules.next().unwrap().as_str().to_string();
+	inner_rules.next(); // Static parameters, bound in the scope
+	let args_in = parse_arg_list(inner_rules.next().unwrap(), scope)?;
+	let args_out = parse_arg_list(inner_rules.next().unwrap(), scope)?;
+	let locals = parse_local(inner_rules.next().unwrap(), scope)?;
+	let (body, automata) = parse_eq_list(inner_rules.next().unwrap(), scope)?;
+	Ok(Node{name, args_in, args_out, locals, body, automata, span})
+}
+
+fn parse_static_param(pair: Pair<Rule>, scope: &Scope) -> Result<(String, Type), CompileError> {
+	assert!(pair.as_rule() == Rule::static_param);
+
+	let span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	let name = inner_rules.next().unwrap().as_str().to_string();
+	let t = parse_type(inner_rules.next().unwrap(), scope)?;
+	match t {
+		Type::Bool | Type::Int | Type::Float | Type::Enum(_) => Ok((name, t)),
+		_ => error(CompileError::Type, &span, format!("static parameter `{}` has type {}, but only booleans, integers, floats and enumerations are allowed", &name, format_type(&t))),
+	}
+}
+
+/// Parses a node with static parameters, if any.
+fn parse_generic_node(pair: Pair<Rule>, scope: &Scope) -> Result<Option<GenericNode>, CompileError> {
+	assert!(pair.as_rule() == Rule::node);
+
+	let span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	let name = inner_rules.next().unwrap().as_str().to_string();
+	let statics: Vec<(String, Type)> = inner_rules.next().unwrap().into_inner().map(|p| {
+		parse_static_param(p, scope)
+	}).collect::<Result<_, _>>()?;
+	if statics.is_empty() {
+		return Ok(None);
+	}
+	Ok(Some(GenericNode{name, statics, span, body: None}))
+}
+
+/// Parses the instance of a generic node, with its static parameters bound to their value.
+fn parse_instance(instance: &Instance, scope: &Scope) -> Result<(Node, Vec<Instance>), CompileError> {
+	let generic = scope.generics.iter().find(|g| g.name == instance.generic).unwrap();
+	let statics = generic.statics.iter().map(|(name, _)| name.clone()).zip(instance.values.iter().cloned()).collect();
+	let instance_scope = Scope{
+		types: scope.types.clone(),
+		consts: scope.consts.clone(),
+		generics: scope.generics.clone(),
+		statics,
+		instances: RefCell::new(Vec::new()),
+		depth: instance.depth,
+		source: generic.span.source.clone(),
+		offset: generic.span.start,
+	};
+	let pair = match LustreParser::parse(Rule::node, generic.span.as_str()) {
+		Ok(mut pairs) => pairs.next().unwrap(),
+		Err(err) => return Err(syntax_error(err, &generic.span.source, generic.span.start)),
+	};
+	let mut n = parse_node(pair, &instance_scope)?;
+	n.name = instance.name.clone();
+	Ok((n, instance_scope.instances.into_inner()))
+}
+
+/// Value of a static parameter of type `t` when a generic node is parsed by itself.
+fn placeholder(t: &Type, scope: &Scope) -> Const {
+	match t {
+		Type::Bool => Const::Bool(false),
+		Type::Int => Const::Int(1),
+		Type::Float => Const::Float(1.0),
+		Type::Enum(name) => match scope.types.iter().find(|decl| &decl.name == name).map(|decl| &decl.def) {
+			Some(TypeDef::Enum(variants)) => Const::Enum(name.clone(), variants[0].clone()),
+			_ => unreachable!(), // Checked by parse_type
+		},
+		_ => unreachable!(), // Rejected by parse_static_param
+	}
+}
+
+/// Parses a generic node with placeholder static arguments, so that its definitions can be
+/// checked. The instances it requests are dropped.
+fn parse_placeholder(generic: &GenericNode, scope: &Scope) -> Result<Node, CompileError> {
+	let request = Instance{
+		generic: generic.name.clone(),
+		values: generic.statics.iter().map(|(_, t)| placeholder(t, scope)).collect(),
+		name: generic.name.clone(),
+		depth: 0,
+	};
+	Ok(parse_instance(&request, scope)?.0)
+}
+
+fn parse_type_decl(pair: Pair<Rule>, scope: &Scope) -> Result<TypeDecl, CompileError> {
+	assert!(pair.as_rule() == Rule::type_decl);
+
+	let span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	let name = inner_rules.next().unwrap().as_str().to_string();
+	let def = inner_rules.next().unwrap();
+	let def = match def.as_rule() {
+		Rule::record_type => {
+			TypeDef::Record(parse_arg_list(def.into_inner().next().unwrap(), scope)?)
+		},
+		Rule::enum_type => {
+			let variants: Vec<String> = def.into_inner().map(|p| p.as_str().to_string()).collect();
+			for v in &variants {
+				if find_variant(v, scope).is_some() || variants.iter().filter(|other| other == &v).count() > 1 {
+					return error(CompileError::Definition, &span, format!("enum value `{}` is defined twice", v));
+				}
+			}
+			TypeDef::Enum(variants)
+		},
+		_ => unreachable!(),
+	};
+	Ok(TypeDecl{name, def, span})
+}
+
+fn parse_const_decl(pair: Pair<Rule>, scope: &Scope) -> Result<ConstDecl, CompileError> {
+	assert!(pair.as_rule() == Rule::const_decl);
+
+	let span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	Ok(ConstDecl{
 		name: inner_rules.next().unwrap().as_str().to_string(),
-		args_in: parse_arg_list(inner_rules.next().unwrap()),
-		args_out: parse_arg_list(inner_rules.next().unwrap()),
-		locals: parse_local(inner_rules.next().unwrap()),
-		body: parse_eq_list(inner_rules.next().unwrap()),
+		typ: parse_type(inner_rules.next().unwrap(), scope)?,
+		value: parse_expr(inner_rules.next().unwrap(), scope)?,
+		span,
+	})
+}
+
+fn parse_extern_decl(pair: Pair<Rule>, scope: &Scope) -> Result<ExternDecl, CompileError> {
+	assert!(pair.as_rule() == Rule::extern_decl);
+
+	let span = span(&pair, scope);
+	let mut inner_rules = pair.into_inner();
+	Ok(ExternDecl{
+		kind: match inner_rules.next().unwrap().as_str() {
+			"function" => ExternKind::Function,
+			"node" => ExternKind::Node,
+			_ => unreachable!(),
+		},
+		name: inner_rules.next().unwrap().as_str().to_string(),
+		args_in: parse_arg_list(inner_rules.next().unwrap(), scope)?,
+		args_out: parse_arg_list(inner_rules.next().unwrap(), scope)?,
+		span,
+	})
+}
+
+fn parse_header(pair: Pair<Rule>) -> Header {
+	assert!(pair.as_rule() == Rule::header);
+
+	let mut header = Header{package: None, imports: Vec::new()};
+	for decl in pair.into_inner() {
+		match decl.as_rule() {
+			Rule::package_decl => {
+				header.package = Some(decl.into_inner().next().unwrap().as_str().to_string());
+			},
+			Rule::include_decl => {
+				let path = decl.into_inner().next().unwrap().into_inner().next().unwrap();
+				header.imports.push(Import::Include(path.as_str().to_string()));
+			},
+			Rule::uses_decl => {
+				for name in decl.into_inner() {
+					header.imports.push(Import::Uses(name.as_str().to_string()));
+				}
+			},
+			_ => unreachable!(),
+		}
+	}
+	header
+}
+
+fn parse_file(pair: Pair<Rule>, source: &Rc<Source>, deps: &File) -> Result<File, CompileError> {
+	assert!(pair.as_rule() == Rule::decl_list);
+
+	// Types and constants are parsed first, so that nodes can use the ones declared after them.
+	// Other declarations can only use the ones declared before them, or in the loaded files.
+	let mut scope = Scope{
+		types: deps.types.clone(),
+		consts: deps.consts.clone(),
+		generics: deps.generics.clone(),
+		statics: Vec::new(),
+		instances: RefCell::new(Vec::new()),
+		depth: 0,
+		source: source.clone(),
+		offset: 0,
+	};
+	for decl in pair.clone().into_inner() {
+		if decl.as_rule() == Rule::type_decl {
+			let decl = parse_type_decl(decl, &scope)?;
+			if let Some(other) = scope.types.iter().find(|other| other.name == decl.name) {
+				return error(CompileError::Definition, &decl.span, format!("type `{}` is defined twice (first at {:?})", &decl.name, &other.span))
+			}
+			scope.types.push(decl);
+		}
+	}
+	for decl in pair.clone().into_inner() {
+		if decl.as_rule() == Rule::const_decl {
+			let decl = parse_const_decl(decl, &scope)?;
+			if let Some(other) = scope.consts.iter().find(|other| other.name == decl.name) {
+				return error(CompileError::Definition, &decl.span, format!("constant `{}` is defined twice (first at {:?})", &decl.name, &other.span))
+			}
+			scope.consts.push(decl);
+		}
 	}
+	for decl in pair.clone().into_inner() {
+		if decl.as_rule() == Rule::node {
+			if let Some(generic) = parse_generic_node(decl, &scope)? {
+				if let Some(other) = scope.generics.iter().find(|other| other.name == generic.name) {
+					return error(CompileError::Definition, &generic.span, format!("generic node `{}` is defined twice (first at {:?})", &generic.name, &other.span))
+				}
+				scope.generics.push(generic);
+			}
+		}
+	}
+	for i in deps.generics.len()..scope.generics.len() {
+		let body = parse_placeholder(&scope.generics[i], &scope)?;
+		scope.generics[i].body = Some(body);
+	}
+
+	let externs = pair.clone().into_inner().filter(|decl| decl.as_rule() == Rule::extern_decl).map(|decl| {
+		parse_extern_decl(decl, &scope)
+	}).collect::<Result<_, _>>()?;
+	let mut nodes = Vec::new();
+	for decl in pair.into_inner() {
+		if decl.as_rule() == Rule::node && parse_generic_node(decl.clone(), &scope)?.is_none() {
+			nodes.push(parse_node(decl, &scope)?);
+		}
+	}
+
+	// Instances are added before the nodes of the file, and are only created once
+	let mut instances: Vec<Node> = Vec::new();
+	let mut requests = scope.instances.replace(Vec::new());
+	while let Some(request) = requests.pop() {
+		let exists = deps.nodes.iter().chain(instances.iter()).any(|n| n.name == request.name);
+		if !exists {
+			let (n, mut nested) = parse_instance(&request, &scope)?;
+			instances.push(n);
+			requests.append(&mut nested);
+		}
+	}
+	instances.extend(nodes);
+
+	let types = scope.types.split_off(deps.types.len());
+	let consts = scope.consts.split_off(deps.consts.len());
+	let generics = scope.generics.split_off(deps.generics.len());
+	Ok(File{types, consts, externs, generics, nodes: instances})
 }
 
-fn parse_file(pair: Pair<Rule>) -> Vec<Node> {
-	assert!(pair.as_rule() == Rule::node_list);
-	pair.into_inner().map(parse_node).collect()
+/// Parses the header of a file, which lists the files it loads.
+pub fn header(source: &Rc<Source>) -> Result<Header, CompileError> {
+	let mut pairs = LustreParser::parse(Rule::file, &source.text).map_err(|err| syntax_error(err, source, 0))?;
+	Ok(parse_header(pairs.next().unwrap()))
 }
 
-pub fn parse(input: &str) -> Result<Vec<Node>, Error<Rule>> {
-	let mut pair = LustreParser::parse(Rule::file, input)?;
-	eprintln!("{:?}", pair);
-	Ok(parse_file(pair.next().unwrap()))
+/// Parses the declarations of a source file. They can use the declarations of the files it loads,
+/// which are in `deps`.
+pub fn parse(source: &Rc<Source>, deps: &File) -> Result<File, CompileError> {
+	let mut pairs = LustreParser::parse(Rule::file, &source.text).map_err(|err| syntax_error(err, source, 0))?;
+	pairs.next(); // Header
+	parse_file(pairs.next().unwrap(), source, deps)
 }
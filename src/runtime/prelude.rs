@@ -0,0 +1,27 @@
+// Rust implementation of the functions declared in prelude.lus
+
+#[allow(dead_code)]
+fn print(s: &'static str) {
+	println!("{}", s);
+}
+
+#[allow(dead_code)]
+fn cos(f: f32) -> f32 {
+	return f.cos();
+}
+
+#[allow(dead_code)]
+fn sin(f: f32) -> f32 {
+	return f.sin();
+}
+
+#[allow(dead_code)]
+fn float_of_int(i: i32) -> f32 {
+	return i as f32;
+}
+
+#[allow(dead_code)]
+fn int_of_float(f: f32) -> i32 {
+	return f as i32;
+}
+
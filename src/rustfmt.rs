@@ -8,7 +8,9 @@
 // which returns a `NodeMemory`. A node memory will contain two kind of objects:
 //
 // - One field per `fby` operator, which is initialized with the constant on the left of the
-//   operator
+//   operator (either a literal or a reference to a constant declared at the top-level)
+// - One field per `pre` operator, which is left to its default value
+// - One `first` flag per `->` operator, which is only set during the first step
 // - One field per function call, which contains the memory of the callee
 //
 // Each node will translate to a Rust function and will take a mutable reference to its memory as
@@ -16,49 +18,146 @@
 //
 // Once each node has a memory, we can start generating code.
 //
-// When generating a node's code, we replace `fby` operators to an access to the memory field. We
-// also add a function footer to update `fby` memory fields to their next value (expression on the
-// right of `fby`).
+// When generating a node's code, we replace `fby` and `pre` operators to an access to the memory
+// field, and `->` operators to a condition on the `first` flag. We also add a function footer to
+// update memory fields to their next value (expression on the right of `fby`, argument of `pre`,
+// `false` for `first` flags).
 //
 // When calling another node, we borrow a mutable reference to the call memory field. This is
 // possible because we have a mutable reference to our own memory. We provide this "sub-reference"
 // to the callee.
+//
+// When a call is split by the scheduler, the outputs of the callee which only depend on its memory
+// are read first by a `foo_outputs` function, which takes an immutable reference to the memory of
+// `foo` and computes these outputs the same way `foo` does. `foo` is called afterwards, and the
+// outputs which were already read are ignored.
+//
+// Records are compiled to Rust structs, which can be copied unless they contain strings.
+// Enumerations are compiled to Rust enums, which default to their first value.
+//
+// Iterators are compiled to `std::array::from_fn` calls, or a loop for `red`. When the iterated
+// node has a memory, the call memory field is an array containing one memory per item.
+//
+// External functions are called as-is, and must be implemented by Rust functions with the same
+// name, taking and returning the Rust types of their arguments. External nodes are also given a
+// memory: the implementation of `extern node foo` must provide a `MemFoo` struct, which implements
+// `Debug` and `Default`, and a `foo` function taking a `&mut MemFoo` before its arguments.
+//
+// Expressions are written with Rust operators, whose precedence differs from Lustre: operands are
+// parenthesized whenever the Rust precedence rules would group them differently from the AST.
+//
+// Equations on a sub-clock are wrapped in an `if` block: they are only computed when their clock
+// is active, and are left to their default value otherwise. In particular, nodes called on a
+// sub-clock are only stepped when it's active. Likewise, memory fields are only updated when the
+// clock of their equation is active.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Write, Result};
+use crate::diagnostic::CompileError;
 use crate::nast::*;
-use crate::typer::type_of_const;
+use crate::typer::type_of_names;
+
+/// Rust implementation of the prelude.
+const PRELUDE: &str = include_str!("runtime/prelude.rs");
 
-fn format_const(w: &mut Write, c: &Const) -> Result<()> {
+fn format_const(w: &mut dyn Write, c: &Const) -> Result<()> {
 	match c {
 		Const::Unit => write!(w, "()"),
 		Const::Bool(b) => write!(w, "{}", b),
 		Const::Int(i) => write!(w, "{}", i),
 		// Need to always use a dot for Rust to understand it's a float constant
-		Const::Float(f) => write!(w, "{:?}", f),
+		Const::Float(f) if f.is_finite() => write!(w, "{:?}", f),
+		// Folded divisions by zero
+		Const::Float(f) if f.is_nan() => write!(w, "f32::NAN"),
+		Const::Float(f) if *f > 0.0 => write!(w, "f32::INFINITY"),
+		Const::Float(_) => write!(w, "f32::NEG_INFINITY"),
 		Const::String(s) => write!(w, "\"{}\"", s), // TODO: escaping
+		Const::Array(consts) => {
+			write!(w, "[")?;
+			let mut first = true;
+			for c in consts {
+				if !first {
+					write!(w, ", ")?;
+				}
+				first = false;
+				format_const(w, c)?;
+			}
+			write!(w, "]")
+		},
+		Const::Record(name, values) => {
+			write!(w, "{} {{ ", capitalize(name))?;
+			let mut first = true;
+			for (field, c) in values {
+				if !first {
+					write!(w, ", ")?;
+				}
+				first = false;
+				write!(w, "{}: ", field)?;
+				format_const(w, c)?;
+			}
+			write!(w, " }}")
+		},
+		Const::Enum(name, variant) => write!(w, "{}::{}", capitalize(name), variant),
 	}
 }
 
-fn format_atom(w: &mut Write, atom: &Atom) -> Result<()> {
+fn format_atom(w: &mut dyn Write, atom: &Atom) -> Result<()> {
 	match atom {
 		Atom::Const(c) => format_const(w, c),
-		Atom::Ident(ident) => write!(w, "{}", ident),
+		Atom::Ident(ident) | Atom::Global(ident) => write!(w, "{}", ident),
+	}
+}
+
+/// Returns the precedence of the Rust expression written for a basic expression. Operands with a
+/// lower precedence than their operator are parenthesized.
+fn precedence(bexpr: &Bexpr) -> u8 {
+	match bexpr {
+		Bexpr::Binop(op, _) => binop_precedence(*op),
+		Bexpr::Unop(_, _) => 8,
+		Bexpr::When(e, _, _) => precedence(e),
+		// `if` and `match` blocks are always parenthesized when used as operands
+		Bexpr::If(_) | Bexpr::Merge(_, _) => 0,
+		_ => 9,
 	}
 }
 
-fn format_bexpr(w: &mut Write, bexpr: &Bexpr) -> Result<()> {
+fn binop_precedence(op: Binop) -> u8 {
+	match op {
+		Binop::Or => 1,
+		Binop::And => 2,
+		Binop::Lt | Binop::Gt | Binop::Leq | Binop::Geq | Binop::Eq | Binop::Neq => 3,
+		Binop::Xor => 4,
+		Binop::Plus | Binop::PlusDot | Binop::Minus | Binop::MinusDot => 5,
+		Binop::Mult | Binop::MultDot | Binop::Div | Binop::DivDot => 6,
+	}
+}
+
+/// Writes an operand, with parentheses if its precedence is lower than `min`.
+fn format_operand(w: &mut dyn Write, bexpr: &Bexpr, min: u8) -> Result<()> {
+	if precedence(bexpr) < min {
+		write!(w, "(")?;
+		format_bexpr(w, bexpr)?;
+		write!(w, ")")
+	} else {
+		format_bexpr(w, bexpr)
+	}
+}
+
+fn format_bexpr(w: &mut dyn Write, bexpr: &Bexpr) -> Result<()> {
 	match bexpr {
 		Bexpr::Unop(op, e) => {
 			write!(w, "{} ", match op {
 				Unop::Minus | Unop::MinusDot => "-",
 				Unop::Not => "!",
 			})?;
-			format_bexpr(w, e)
+			format_operand(w, e, precedence(bexpr))
 		},
 		Bexpr::Binop(op, exprs) => {
-			let (e1, e2): &(Bexpr, Bexpr) = &*exprs;
-			format_bexpr(w, e1)?;
+			let (e1, e2): &(Bexpr, Bexpr) = exprs;
+			let prec = binop_precedence(*op);
+			// Operators are left-associative, except comparisons which cannot be chained
+			let left_min = if prec == 3 { prec + 1 } else { prec };
+			format_operand(w, e1, left_min)?;
 			write!(w, " {} ", match op {
 				Binop::Plus | Binop::PlusDot => "+",
 				Binop::Minus | Binop::MinusDot => "-",
@@ -69,13 +168,15 @@ fn format_bexpr(w: &mut Write, bexpr: &Bexpr) -> Result<()> {
 				Binop::Leq => "<=",
 				Binop::Geq => ">=",
 				Binop::Eq => "==",
+				Binop::Neq => "!=",
 				Binop::And => "&&",
 				Binop::Or => "||",
+				Binop::Xor => "^",
 			})?;
-			format_bexpr(w, e2)
+			format_operand(w, e2, prec + 1)
 		},
 		Bexpr::If(iff) => {
-			let (cond, body, else_part): &(Bexpr, Bexpr, Bexpr) = &*iff;
+			let (cond, body, else_part): &(Bexpr, Bexpr, Bexpr) = iff;
 			write!(w, "if ")?;
 			format_bexpr(w, cond)?;
 			write!(w, " {{ ")?;
@@ -98,22 +199,260 @@ fn format_bexpr(w: &mut Write, bexpr: &Bexpr) -> Result<()> {
 				None => unreachable!(),
 			}
 		},
+		Bexpr::When(e, _, _) => format_bexpr(w, e),
+		Bexpr::Merge(clock, cases) => {
+			write!(w, "match {} {{ ", clock)?;
+			for (value, e) in cases {
+				format_const(w, value)?;
+				write!(w, " => ")?;
+				format_bexpr(w, e)?;
+				write!(w, ", ")?;
+			}
+			write!(w, "}}")
+		},
+		Bexpr::Array(vex) => {
+			write!(w, "[")?;
+			let mut first = true;
+			for e in vex {
+				if !first {
+					write!(w, ", ")?;
+				}
+				first = false;
+				format_bexpr(w, e)?;
+			}
+			write!(w, "]")
+		},
+		Bexpr::Repeat(e, size) => {
+			write!(w, "[")?;
+			format_bexpr(w, e)?;
+			write!(w, "; {}]", size)
+		},
+		Bexpr::Index(atom, i) => {
+			format_atom(w, atom)?;
+			match &**i {
+				Bexpr::Atom(Atom::Const(c)) => {
+					write!(w, "[")?;
+					format_const(w, c)?;
+					write!(w, "]")
+				},
+				_ => {
+					write!(w, "[(")?;
+					format_bexpr(w, i)?;
+					write!(w, ") as usize]")
+				},
+			}
+		},
+		Bexpr::Record(name, values) => {
+			write!(w, "{} {{ ", capitalize(name))?;
+			let mut first = true;
+			for (field, e) in values {
+				if !first {
+					write!(w, ", ")?;
+				}
+				first = false;
+				write!(w, "{}: ", field)?;
+				format_bexpr(w, e)?;
+			}
+			write!(w, " }}")
+		},
+		Bexpr::Field(e, field) => {
+			match &**e {
+				Bexpr::Atom(_) | Bexpr::Field(_, _) | Bexpr::Index(_, _) => format_bexpr(w, e)?,
+				_ => {
+					write!(w, "(")?;
+					format_bexpr(w, e)?;
+					write!(w, ")")?;
+				},
+			}
+			write!(w, ".{}", field)
+		},
 		Bexpr::Atom(atom) => format_atom(w, atom),
 	}
 }
 
-fn format_expr(w: &mut Write, e: &Expr, dest: &[String], mems: &HashMap<String, NodeMemory>) -> Result<()> {
+/// Writes the condition for a clock variable to be equal to a value.
+fn format_clock_cond(w: &mut dyn Write, clock: &str, value: &Const) -> Result<()> {
+	match value {
+		Const::Bool(true) => write!(w, "{}", clock),
+		Const::Bool(false) => write!(w, "!{}", clock),
+		_ => {
+			write!(w, "{} == ", clock)?;
+			format_const(w, value)
+		},
+	}
+}
+
+/// Writes the condition for a clock to be active. The base clock is always active.
+fn format_clock(w: &mut dyn Write, c: &Clock) -> Result<()> {
+	match c {
+		Clock::Base => write!(w, "true"),
+		Clock::On(parent, clock, value) => {
+			if **parent != Clock::Base {
+				format_clock(w, parent)?;
+				write!(w, " && ")?;
+			}
+			format_clock_cond(w, clock, value)
+		},
+	}
+}
+
+fn clock_of_var(n: &Node, name: &str) -> Clock {
+	match n.body.iter().find(|eq| eq.names.iter().any(|s| s == name)) {
+		Some(eq) => eq.clock.clone(),
+		None => Clock::Base,
+	}
+}
+
+/// Writes a call to an iterated node on the item `_i` of the array arguments. The first `n_acc`
+/// arguments are passed as-is, and are replaced with the accumulator `_acc` if any.
+fn format_iterated_call(w: &mut dyn Write, name: &str, args: &[Atom], n_acc: usize, dest: &[String], mems: &HashMap<String, NodeMemory>) -> Result<()> {
+	write!(w, "{}(", name)?;
+	let mut first = true;
+	if mems.get(name).is_some() {
+		write!(w, "&mut mem.{}[_i]", mem_field(dest))?;
+		first = false;
+	}
+	for (i, arg) in args.iter().enumerate() {
+		if !first {
+			write!(w, ", ")?;
+		}
+		first = false;
+		if i < n_acc {
+			write!(w, "_acc")?;
+		} else {
+			format_atom(w, arg)?;
+			write!(w, "[_i]")?;
+		}
+	}
+	write!(w, ")")
+}
+
+/// Writes a tuple of arrays built from the array of tuples `_ys`, which has `len` items.
+fn format_unzip(w: &mut dyn Write, n_items: usize, len: usize) -> Result<()> {
+	let mut first = true;
+	for i in 0..n_items {
+		if !first {
+			write!(w, ", ")?;
+		}
+		first = false;
+		write!(w, "std::array::from_fn::<_, {}, _>(|_i| _ys[_i].{})", len, i)?;
+	}
+	Ok(())
+}
+
+fn format_iterate(w: &mut dyn Write, e: &Expr, dest: &[String], n: &Node, mems: &HashMap<String, NodeMemory>) -> Result<()> {
+	let (iter, name, size, args) = match e {
+		Expr::Iterate{iter, name, size, args} => (*iter, name, *size, args),
+		_ => unreachable!(),
+	};
+	// Number of results of the node which are gathered in arrays
+	let n_results = match type_of_names(n, dest) {
+		Type::Tuple(types) => types.len(),
+		_ => 1,
+	};
+	// A fold whose only result is the accumulator is a red
+	let iter = match iter {
+		Iter::Fold if n_results == 1 => Iter::Red,
+		_ => iter,
+	};
+	match iter {
+		Iter::Map => {
+			if n_results == 1 {
+				write!(w, "std::array::from_fn::<_, {}, _>(|_i| ", size)?;
+				format_iterated_call(w, name, args, 0, dest, mems)?;
+				write!(w, ")")
+			} else {
+				write!(w, "{{ let _ys = std::array::from_fn::<_, {}, _>(|_i| ", size)?;
+				format_iterated_call(w, name, args, 0, dest, mems)?;
+				write!(w, "); (")?;
+				format_unzip(w, n_results, size)?;
+				write!(w, ") }}")
+			}
+		},
+		Iter::Red => {
+			write!(w, "{{ let mut _acc = ")?;
+			format_atom(w, &args[0])?;
+			write!(w, "; for _i in 0..{} {{ _acc = ", size)?;
+			format_iterated_call(w, name, args, 1, dest, mems)?;
+			write!(w, "; }} _acc }}")
+		},
+		Iter::Fold => {
+			// The accumulator is the first result
+			let n_items = n_results - 1;
+			let items: Vec<String> = (0..n_items).map(|i| format!("_y{}", i)).collect();
+			write!(w, "{{ let mut _acc = ")?;
+			format_atom(w, &args[0])?;
+			write!(w, "; let _ys = std::array::from_fn::<_, {}, _>(|_i| {{ ", size)?;
+			write!(w, "let (_next, {}) = ", items.join(", "))?;
+			format_iterated_call(w, name, args, 1, dest, mems)?;
+			if n_items == 1 {
+				write!(w, "; _acc = _next; _y0 }}); (_acc, _ys) }}")
+			} else {
+				write!(w, "; _acc = _next; ({}) }}); (_acc, ", items.join(", "))?;
+				format_unzip(w, n_items, size)?;
+				write!(w, ") }}")
+			}
+		},
+	}
+}
+
+/// Writes the opening of a block restoring the default memory of a callee, when the reset condition
+/// is true.
+fn format_reset(w: &mut dyn Write, cond: &Atom, dest: &[String]) -> Result<()> {
+	write!(w, "{{ if ")?;
+	format_atom(w, cond)?;
+	write!(w, " {{ mem.{} = Default::default(); }} ", mem_field(dest))
+}
+
+/// Writes a call which steps a node. The memory of the callee is named after `dest`.
+fn format_call(w: &mut dyn Write, name: &str, args: &[Bexpr], dest: &[String], mems: &HashMap<String, NodeMemory>) -> Result<()> {
+	write!(w, "{}(", name)?;
+	let mut first = true;
+	if mems.get(name).is_some() {
+		if dest.is_empty() {
+			// Used in main()
+			write!(w, "&mut mem")?;
+		} else {
+			write!(w, "&mut mem.{}", mem_field(dest))?;
+		}
+		first = false;
+	}
+	for arg in args {
+		if !first {
+			write!(w, ", ")?;
+		}
+		first = false;
+		format_bexpr(w, arg)?;
+	}
+	write!(w, ")")
+}
+
+fn format_expr(w: &mut dyn Write, e: &Expr, dest: &[String], n: &Node, mems: &HashMap<String, NodeMemory>) -> Result<()> {
 	match e {
-		Expr::Call{name, args} => {
-			write!(w, "{}(", name)?;
+		Expr::Call{name, args, reset} => {
+			let reset = match reset {
+				Some(cond) if mems.get(name).is_some() => Some(cond),
+				_ => None,
+			};
+			if let Some(cond) = reset {
+				format_reset(w, cond, dest)?;
+			}
+			format_call(w, name, args, dest, mems)?;
+			if reset.is_some() {
+				write!(w, " }}")?;
+			}
+			Ok(())
+		},
+		Expr::Outputs{name, call, group, args, reset, ..} => {
+			let has_mem = mems.get(name).is_some();
+			let reset = reset.as_ref().filter(|_| has_mem);
+			if let Some(cond) = reset {
+				format_reset(w, cond, call)?;
+			}
+			write!(w, "{}_outputs_{}(", name, group)?;
 			let mut first = true;
-			if let Some(_) = mems.get(name) {
-				if dest.is_empty() {
-					// Used in main()
-					write!(w, "&mut mem")?;
-				} else {
-					write!(w, "&mut mem.{}", dest.join("_"))?;
-				}
+			if has_mem {
+				write!(w, "&mem.{}", mem_field(call))?;
 				first = false;
 			}
 			for arg in args {
@@ -123,34 +462,72 @@ fn format_expr(w: &mut Write, e: &Expr, dest: &[String], mems: &HashMap<String,
 				first = false;
 				format_bexpr(w, arg)?;
 			}
-			write!(w, ")")
+			write!(w, ")")?;
+			if reset.is_some() {
+				write!(w, " }}")?;
+			}
+			Ok(())
 		},
-		Expr::Fby(_, _) => {
-			write!(w, "mem.{}", dest.join("_"))
+		Expr::Step{name, args, call} => format_call(w, name, args, call, mems),
+		Expr::Iterate{..} => format_iterate(w, e, dest, n, mems),
+		Expr::Fby(_, _) | Expr::Pre(_) => {
+			write!(w, "mem.{}", mem_field(dest))
+		},
+		Expr::Arrow(first, next) => {
+			write!(w, "if mem.{} {{ ", first_flag(dest))?;
+			format_bexpr(w, &bexpr_from_vec(first.clone()))?;
+			write!(w, " }} else {{ ")?;
+			format_bexpr(w, &bexpr_from_vec(next.clone()))?;
+			write!(w, " }}")
+		},
+		Expr::Current(atom) => {
+			// Only the last sampling matters, the parent clock is already active
+			match atom {
+				Atom::Ident(name) => match clock_of_var(n, name) {
+					Clock::On(_, clock, value) => {
+						write!(w, "if ")?;
+						format_clock_cond(w, &clock, &value)?;
+						write!(w, " {{ {} }} else {{ mem.{} }}", name, mem_field(dest))
+					},
+					Clock::Base => unreachable!(),
+				},
+				Atom::Const(_) | Atom::Global(_) => unreachable!(),
+			}
 		},
 		Expr::Bexpr(bexpr) => format_bexpr(w, bexpr),
 	}
 }
 
-fn format_equation(w: &mut Write, eq: &Equation, mems: &HashMap<String, NodeMemory>) -> Result<()> {
+fn format_equation(w: &mut dyn Write, eq: &Equation, n: &Node, mems: &HashMap<String, NodeMemory>) -> Result<()> {
+	// Results of a split call which are defined by the other part are ignored
+	let names: Vec<&String> = match &eq.body {
+		Expr::Outputs{call, outputs, ..} => outputs.iter().map(|k| &call[*k]).collect(),
+		Expr::Step{call, ..} => call.iter().collect(),
+		_ => eq.names.iter().collect(),
+	};
+	let names: Vec<&str> = names.into_iter().map(|name| {
+		if eq.names.contains(name) { name.as_str() } else { "_" }
+	}).collect();
 	write!(w, "\tlet ")?;
-	if eq.names.len() != 1 {
+	if names.len() != 1 {
 		write!(w, "(")?;
 	}
-	let mut first = true;
-	for name in &eq.names {
-		if !first {
-			write!(w, ", ")?;
-		}
-		first = false;
-		write!(w, "{}", name)?;
-	}
-	if eq.names.len() != 1 {
+	write!(w, "{}", names.join(", "))?;
+	if names.len() != 1 {
 		write!(w, ")")?;
 	}
 	write!(w, " = ")?;
-	format_expr(w, &eq.body, &eq.names, mems)?;
-	write!(w, ";\n")
+	if eq.clock == Clock::Base {
+		format_expr(w, &eq.body, &eq.names, n, mems)?;
+	} else {
+		// Variables on an inactive clock are left to their default value
+		write!(w, "if ")?;
+		format_clock(w, &eq.clock)?;
+		write!(w, " {{ ")?;
+		format_expr(w, &eq.body, &eq.names, n, mems)?;
+		write!(w, " }} else {{ Default::default() }}")?;
+	}
+	writeln!(w, ";")
 }
 
 fn get_type(typ: &Type) -> String {
@@ -159,7 +536,8 @@ fn get_type(typ: &Type) -> String {
 		Type::Bool => "bool".to_string(),
 		Type::Int => "i32".to_string(),
 		Type::Float => "f32".to_string(),
-		Type::String => "String".to_string(),
+		// Strings are literals or read once in main(), so that all values can be copied
+		Type::String => "&'static str".to_string(),
 		Type::Tuple(types) => {
 			let mut s = String::new();
 			s += "(";
@@ -174,10 +552,12 @@ fn get_type(typ: &Type) -> String {
 			s += ")";
 			s
 		},
+		Type::Array(t, size) => format!("[{}; {}]", get_type(t), size),
+		Type::Record(name) | Type::Enum(name) => capitalize(name),
 	}
 }
 
-fn format_arg_list(w: &mut Write, args: &HashMap<String, Type>, with_name: bool, with_typ: bool) -> Result<()> {
+fn format_arg_list(w: &mut dyn Write, args: &[(String, Type)], with_name: bool, with_typ: bool) -> Result<()> {
 	let mut first = true;
 	for (name, typ) in args {
 		if !first {
@@ -197,45 +577,105 @@ fn format_arg_list(w: &mut Write, args: &HashMap<String, Type>, with_name: bool,
 	Ok(())
 }
 
-fn format_struct(w: &mut Write, name: &str, fields: &HashMap<String, String>, init_values: &HashMap<String, Vec<Const>>) -> Result<()> {
-	write!(w, "#[derive(Debug)]\n")?;
-	write!(w, "struct {} {{\n", name)?;
+/// Writes the default value of a Rust type. `Default` is only implemented for arrays of up to 32
+/// items.
+fn format_default(w: &mut dyn Write, t: &str) -> Result<()> {
+	if t.starts_with('[') {
+		write!(w, "std::array::from_fn(|_| Default::default())")
+	} else {
+		write!(w, "Default::default()")
+	}
+}
+
+/// Writes an argument list, in parentheses if there are several arguments.
+fn format_tuple(w: &mut dyn Write, args: &[(String, Type)], with_name: bool, with_typ: bool) -> Result<()> {
+	if args.len() > 1 {
+		write!(w, "(")?;
+	}
+	format_arg_list(w, args, with_name, with_typ)?;
+	if args.len() > 1 {
+		write!(w, ")")?;
+	}
+	Ok(())
+}
+
+fn format_struct(w: &mut dyn Write, derive: &str, name: &str, fields: &[(String, String)], init_values: &HashMap<String, Vec<Atom>>) -> Result<()> {
+	writeln!(w, "#[derive({})]", derive)?;
+	writeln!(w, "struct {} {{", name)?;
 	for (k, t) in fields {
-		write!(w, "\t{}: {},\n", k, t)?;
+		writeln!(w, "\t{}: {},", k, t)?;
 	}
 	write!(w, "}}\n\n")?;
 
-	write!(w, "impl Default for {} {{\n", name)?;
-	write!(w, "\tfn default() -> Self {{\n")?;
-	write!(w, "\t\tSelf {{\n")?;
-	for (k, _) in fields {
+	writeln!(w, "impl Default for {} {{", name)?;
+	writeln!(w, "\tfn default() -> Self {{")?;
+	writeln!(w, "\t\tSelf {{")?;
+	for (k, t) in fields {
 		write!(w, "\t\t\t{}: ", k)?;
 		match init_values.get(k) {
-			Some(consts) => {
-				if consts.len() == 1 {
-					format_const(w, &consts[0])?;
+			Some(atoms) => {
+				if atoms.len() == 1 {
+					format_atom(w, &atoms[0])?;
 				} else {
 					write!(w, "(")?;
 					let mut first = true;
-					for c in consts {
+					for a in atoms {
 						if !first {
 							write!(w, ", ")?;
 						}
 						first = false;
-						format_const(w, c)?;
+						format_atom(w, a)?;
 					}
 					write!(w, ")")?;
 				}
 			},
-			None => write!(w, "Default::default()")?,
+			None => format_default(w, t)?,
 		}
-		write!(w, ",\n")?;
+		writeln!(w, ",")?;
 	}
-	write!(w, "\t\t}}\n")?;
-	write!(w, "\t}}\n")?;
+	writeln!(w, "\t\t}}")?;
+	writeln!(w, "\t}}")?;
 	write!(w, "}}\n\n")
 }
 
+fn format_type_decl(w: &mut dyn Write, decl: &TypeDecl) -> Result<()> {
+	match &decl.def {
+		TypeDef::Record(fields) => {
+			let fields: Vec<(String, String)> = fields.iter().map(|(field, t)| (field.clone(), get_type(t))).collect();
+			format_struct(w, "Debug, Clone, Copy, PartialEq", &capitalize(&decl.name), &fields, &HashMap::new())
+		},
+		TypeDef::Enum(variants) => {
+			let name = capitalize(&decl.name);
+			writeln!(w, "#[allow(dead_code)]")?;
+			writeln!(w, "#[derive(Debug, Clone, Copy, PartialEq)]")?;
+			writeln!(w, "enum {} {{", name)?;
+			for v in variants {
+				writeln!(w, "\t{},", v)?;
+			}
+			write!(w, "}}\n\n")?;
+
+			writeln!(w, "impl Default for {} {{", name)?;
+			writeln!(w, "\tfn default() -> Self {{")?;
+			writeln!(w, "\t\t{}::{}", name, variants[0])?;
+			writeln!(w, "\t}}")?;
+			write!(w, "}}\n\n")?;
+
+			// Used to read inputs in main()
+			writeln!(w, "impl std::str::FromStr for {} {{", name)?;
+			write!(w, "\ttype Err = String;\n\n")?;
+			writeln!(w, "\tfn from_str(s: &str) -> Result<Self, Self::Err> {{")?;
+			writeln!(w, "\t\tmatch s {{")?;
+			for v in variants {
+				writeln!(w, "\t\t\t\"{}\" => Ok({}::{}),", v, name, v)?;
+			}
+			writeln!(w, "\t\t\t_ => Err(format!(\"invalid {}: {{}}\", s)),", decl.name)?;
+			writeln!(w, "\t\t}}")?;
+			writeln!(w, "\t}}")?;
+			write!(w, "}}\n\n")
+		},
+	}
+}
+
 fn capitalize(s: &str) -> String {
 	let mut c = s.chars();
 	match c.next() {
@@ -248,67 +688,114 @@ fn bexpr_from_vec(v: Vec<Bexpr>) -> Bexpr {
 	if v.is_empty() {
 		Bexpr::Atom(Atom::Const(Const::Unit))
 	} else if v.len() == 1 {
-		v.into_iter().nth(0).unwrap()
+		v.into_iter().next().unwrap()
 	} else {
 		Bexpr::Tuple(v)
 	}
 }
 
+/// Returns the name of the memory field of the equation defining `names`. Each variable is defined
+/// by a single equation, so the first one is enough to name it.
+fn mem_field(names: &[String]) -> &str {
+	&names[0]
+}
+
+/// Returns the name of the flag of a `->` defining `names`, which is only set during the first
+/// step. It starts with `_`, which Lustre identifiers cannot, so that it is not the field of a
+/// variable.
+fn first_flag(names: &[String]) -> String {
+	format!("_{}_first", mem_field(names))
+}
+
 struct NodeMemory {
 	name: String,
-	/// Name and type of each field
-	fields: HashMap<String, String>,
-	init_values: HashMap<String, Vec<Const>>,
-	next_values: HashMap<String, Bexpr>,
+	/// Name and type of each field, in the order of the equations
+	fields: Vec<(String, String)>,
+	init_values: HashMap<String, Vec<Atom>>,
+	/// Next value of each field, updated when its clock is active
+	next_values: Vec<(String, Clock, Bexpr)>,
 }
 
 fn get_node_mem(n: &Node, mems: &HashMap<String, NodeMemory>) -> Option<NodeMemory> {
-	let mut fields = HashMap::new(); // Memory fields (both for function calls and `fby`)
+	let mut fields = Vec::new(); // Memory fields (both for function calls and `fby`)
 	let mut init_values = HashMap::new(); // Initialization values for each field (only for `fby`)
-	let mut next_values = HashMap::new(); // Next values for each field (only for `fby`)
+	let mut next_values = Vec::new(); // Next values for each field (only for `fby`)
 	for eq in &n.body {
-		let dest = eq.names.join("_");
+		// Steps of split calls may not define any variable, the memory is named after the whole call
+		let dest = match &eq.body {
+			Expr::Step{call, ..} => mem_field(call),
+			_ => mem_field(&eq.names),
+		}.to_string();
 		match &eq.body {
-			Expr::Call{name, args: _} => {
+			Expr::Call{name, ..} => {
 				if let Some(call_mem) = mems.get(name) {
-					fields.insert(dest, call_mem.name.clone());
+					fields.push((dest, call_mem.name.clone()));
+				}
+			},
+			Expr::Step{name, ..} => {
+				if let Some(call_mem) = mems.get(name) {
+					fields.push((dest, call_mem.name.clone()));
+				}
+			},
+			Expr::Iterate{name, size, ..} => {
+				// One memory per item
+				if let Some(call_mem) = mems.get(name) {
+					fields.push((dest, format!("[{}; {}]", call_mem.name, size)));
 				}
 			},
 			Expr::Fby(init, next) => {
-				let init: Vec<Const> = init.iter().map(|atom| match atom {
-					Atom::Const(c) => c.clone(),
-					_ => unreachable!(),
+				let init: Vec<Atom> = init.iter().map(|atom| match atom {
+					Atom::Const(_) | Atom::Global(_) => atom.clone(),
+					// Initial values which are not constant are normalized to `->` (see `normalizer.rs`)
+					Atom::Ident(_) => unreachable!(),
 				}).collect();
 				let next = bexpr_from_vec(next.clone());
-				let t = match init.len() {
-					0 => Type::Unit,
-					1 => type_of_const(&init[0]),
-					_ => Type::Tuple(init.iter().map(type_of_const).collect()),
-				};
+				let t = type_of_names(n, &eq.names);
 				init_values.insert(dest.clone(), init.clone());
-				next_values.insert(dest.clone(), next.clone());
-				fields.insert(dest.clone(), get_type(&t).to_string());
+				next_values.push((dest.clone(), eq.clock.clone(), next.clone()));
+				fields.push((dest.clone(), get_type(&t).to_string()));
+			},
+			Expr::Pre(next) => {
+				// No initialization value: the field is left to its default value
+				let t = type_of_names(n, &eq.names);
+				next_values.push((dest.clone(), eq.clock.clone(), bexpr_from_vec(next.clone())));
+				fields.push((dest.clone(), get_type(&t).to_string()));
+			},
+			Expr::Arrow(_, _) => {
+				// Flag which is only set during the first step
+				let first = first_flag(&eq.names);
+				init_values.insert(first.clone(), vec![Atom::Const(Const::Bool(true))]);
+				let next = Bexpr::Atom(Atom::Const(Const::Bool(false)));
+				next_values.push((first.clone(), eq.clock.clone(), next));
+				fields.push((first, get_type(&Type::Bool)));
+			},
+			Expr::Current(_) => {
+				// Holds the last value of the operand
+				let t = type_of_names(n, &eq.names);
+				let next = eq.names.iter().map(|name| Bexpr::Atom(Atom::Ident(name.clone()))).collect();
+				next_values.push((dest.clone(), eq.clock.clone(), bexpr_from_vec(next)));
+				fields.push((dest.clone(), get_type(&t).to_string()));
 			},
 			_ => {},
 		}
 	}
 
-	if fields.len() == 0 {
+	if fields.is_empty() {
 		None
 	} else {
 		Some(NodeMemory{
 			name: format!("Mem{}", capitalize(&n.name)),
-			fields: fields,
-			init_values: init_values,
-			next_values: next_values,
+			fields,
+			init_values,
+			next_values,
 		})
 	}
 }
 
-fn format_node(w: &mut Write, n: &Node, mems: &HashMap<String, NodeMemory>) -> Result<()> {
+fn format_node(w: &mut dyn Write, n: &Node, mems: &HashMap<String, NodeMemory>) -> Result<()> {
 	let mem = mems.get(&n.name);
 	if let Some(mem) = mem {
-		format_struct(w, &mem.name, &mem.fields, &mem.init_values)?;
+		format_struct(w, "Debug", &mem.name, &mem.fields, &mem.init_values)?;
 	}
 
 	write!(w, "fn {}(", &n.name)?;
@@ -320,104 +807,161 @@ fn format_node(w: &mut Write, n: &Node, mems: &HashMap<String, NodeMemory>) -> R
 	}
 	format_arg_list(w, &n.args_in, true, true)?;
 	write!(w, ") -> ")?;
-	if n.args_out.len() > 1 {
-		write!(w, "(")?;
-	}
-	format_arg_list(w, &n.args_out, false, true)?;
-	if n.args_out.len() > 1 {
-		write!(w, ")")?;
-	}
-	write!(w, " {{\n")?;
+	format_tuple(w, &n.args_out, false, true)?;
+	writeln!(w, " {{")?;
 	for eq in &n.body {
-		format_equation(w, eq, mems)?;
+		format_equation(w, eq, n, mems)?;
 	}
 
 	if let Some(mem) = mem {
-		for (k, v) in &mem.next_values {
-			write!(w, "\tmem.{} = ", k)?;
+		for (k, clock, v) in &mem.next_values {
+			write!(w, "\t")?;
+			if *clock != Clock::Base {
+				write!(w, "if ")?;
+				format_clock(w, clock)?;
+				write!(w, " {{ ")?;
+			}
+			write!(w, "mem.{} = ", k)?;
 			format_bexpr(w, v)?;
-			write!(w, ";\n")?;
+			write!(w, ";")?;
+			if *clock != Clock::Base {
+				write!(w, " }}")?;
+			}
+			writeln!(w)?;
 		}
 	}
 
 	write!(w, "\treturn ")?;
-	if n.args_out.len() > 1 {
-		write!(w, "(")?;
+	format_tuple(w, &n.args_out, true, false)?;
+	writeln!(w, ";")?;
+	write!(w, "}}\n\n")
+}
+
+/// Writes the function reading the group of outputs `g` of a node from its memory and the inputs
+/// they depend on, which is called before the node is stepped when a call is split.
+fn format_outputs(w: &mut dyn Write, n: &Node, g: usize, mems: &HashMap<String, NodeMemory>) -> Result<()> {
+	let group = &n.groups[g];
+	let inputs: Vec<(String, Type)> = group.inputs.iter().map(|j| n.args_in[*j].clone()).collect();
+	let outputs: Vec<(String, Type)> = group.outputs.iter().map(|k| n.args_out[*k].clone()).collect();
+	// Groups which only depend on inputs don't read the memory
+	writeln!(w, "#[allow(unused_variables)]")?;
+	write!(w, "fn {}_outputs_{}(", &n.name, g)?;
+	if let Some(mem) = mems.get(&n.name) {
+		write!(w, "mem: &{}", &mem.name)?;
+		if !inputs.is_empty() {
+			write!(w, ", ")?;
+		}
 	}
-	format_arg_list(w, &n.args_out, true, false)?;
-	if n.args_out.len() > 1 {
-		write!(w, ")")?;
+	format_arg_list(w, &inputs, true, true)?;
+	write!(w, ") -> ")?;
+	format_tuple(w, &outputs, false, true)?;
+	writeln!(w, " {{")?;
+	for eq in &group.body {
+		format_equation(w, eq, n, mems)?;
 	}
-	write!(w, ";\n")?;
+	write!(w, "\treturn ")?;
+	format_tuple(w, &outputs, true, false)?;
+	writeln!(w, ";")?;
 	write!(w, "}}\n\n")
 }
 
-pub fn format(w: &mut Write, f: &[Node]) -> Result<()> {
-	// Builtin functions
-	write!(w, "#[allow(dead_code)]\n");
-	write!(w, "fn print(s: &str) {{\n")?;
-	write!(w, "\tprintln!(\"{{}}\", s);\n")?;
-	write!(w, "}}\n\n")?;
-
-	write!(w, "#[allow(dead_code)]\n");
-	write!(w, "fn not(b: bool) -> bool {{\n")?;
-	write!(w, "\treturn !b;\n")?;
-	write!(w, "}}\n\n")?;
-
-	write!(w, "#[allow(dead_code)]\n");
-	write!(w, "fn cos(f: f32) -> f32 {{\n")?;
-	write!(w, "\treturn f.cos();\n")?;
-	write!(w, "}}\n\n")?;
+/// Returns the nodes and the groups of outputs read by split calls.
+fn find_split_calls(body: &[Equation]) -> Vec<(&str, usize)> {
+	body.iter().filter_map(|eq| match &eq.body {
+		Expr::Outputs{name, group, ..} => Some((name.as_str(), *group)),
+		_ => None,
+	}).collect()
+}
 
-	write!(w, "#[allow(dead_code)]\n");
-	write!(w, "fn sin(f: f32) -> f32 {{\n")?;
-	write!(w, "\treturn f.sin();\n")?;
-	write!(w, "}}\n\n")?;
+pub fn format(w: &mut dyn Write, f: &File) -> std::result::Result<(), CompileError> {
+	// Functions of the prelude
+	write!(w, "{}", PRELUDE)?;
 
-	write!(w, "#[allow(dead_code)]\n");
-	write!(w, "fn float_of_int(i: i32) -> f32 {{\n")?;
-	write!(w, "\treturn i as f32;\n")?;
-	write!(w, "}}\n\n")?;
+	// Declared types
+	for decl in &f.types {
+		format_type_decl(w, decl)?;
+	}
 
-	write!(w, "#[allow(dead_code)]\n");
-	write!(w, "fn int_of_float(f: f32) -> i32 {{\n")?;
-	write!(w, "\treturn f as i32;\n")?;
-	write!(w, "}}\n\n")?;
+	// Constants
+	for decl in &f.consts {
+		writeln!(w, "#[allow(dead_code, non_upper_case_globals)]")?;
+		write!(w, "const {}: {} = ", &decl.name, get_type(&decl.typ))?;
+		format_const(w, &decl.value)?;
+		write!(w, ";\n\n")?;
+	}
 
 	// Create one memory per node, if needed
 	let mut mems = HashMap::new();
-	for n in f {
+	for decl in &f.externs {
+		if decl.kind == ExternKind::Node {
+			// The memory struct is provided with the implementation
+			mems.insert(decl.name.clone(), NodeMemory{
+				name: format!("Mem{}", capitalize(&decl.name)),
+				fields: Vec::new(),
+				init_values: HashMap::new(),
+				next_values: Vec::new(),
+			});
+		}
+	}
+	for n in &f.nodes {
 		if let Some(mem) = get_node_mem(n, &mems) {
 			mems.insert(n.name.clone(), mem);
 		}
 	}
 
+	// Find the nodes whose outputs are read before they are stepped, including by other outputs
+	// functions
+	let mut split = HashSet::new();
+	let mut todo: Vec<(&str, usize)> = f.nodes.iter().flat_map(|n| find_split_calls(&n.body)).collect();
+	while let Some((name, g)) = todo.pop() {
+		if split.insert((name, g)) {
+			if let Some(n) = f.nodes.iter().find(|n| n.name == name) {
+				todo.extend(find_split_calls(&n.groups[g].body));
+			}
+		}
+	}
+
 	// Generate code for each node
-	for n in f {
+	for n in &f.nodes {
 		format_node(w, n, &mems)?;
+		for g in 0..n.groups.len() {
+			if split.contains(&(n.name.as_str(), g)) {
+				format_outputs(w, n, g, &mems)?;
+			}
+		}
 	}
 
 	// Call the last node in main()
-	write!(w, "fn main() {{\n")?;
-	if let Some(n) = f.last() {
+	writeln!(w, "fn main() {{")?;
+	if let Some(n) = f.nodes.last() {
 		let num_calls = 10;
-		write!(w, "\teprintln!(\"We will call node `{}` {} times.\");\n", &n.name, num_calls)?;
+		writeln!(w, "\teprintln!(\"We will call node `{}` {} times.\");", &n.name, num_calls)?;
 
 		// Ask input arguments
 		for (name, typ) in &n.args_in {
 			if let Type::Unit = typ {
-				write!(w, "\tlet {} = ();\n", name)?;
+				writeln!(w, "\tlet {} = ();", name)?;
 				continue
 			}
 
-			write!(w, "\teprint!(\"{}: \");\n", name)?;
-			write!(w, "\tlet mut {}_str = String::new();\n", name)?;
-			write!(w, "\tstd::io::stdin().read_line(&mut {}_str).unwrap();\n", name)?;
+			writeln!(w, "\teprint!(\"{}: \");", name)?;
+			writeln!(w, "\tlet mut {}_str = String::new();", name)?;
+			writeln!(w, "\tstd::io::stdin().read_line(&mut {}_str).unwrap();", name)?;
 			match typ {
-				Type::String => write!(w, "\tlet {} = {}_str;\n", name, name)?,
-				_ => write!(w, "\tlet {} = {}_str.trim().parse::<{}>().unwrap();\n", name, name, get_type(typ))?,
+				Type::String => writeln!(w, "\tlet {}: &'static str = Box::leak({}_str.into_boxed_str());", name, name)?,
+				Type::Array(item_typ, _) => {
+					// Items are separated by whitespace
+					if let Type::String = **item_typ {
+						writeln!(w, "\tlet {}_str: &'static str = Box::leak({}_str.into_boxed_str());", name, name)?;
+						writeln!(w, "\tlet {}_items: Vec<&'static str> = {}_str.split_whitespace().collect();", name, name)?;
+					} else {
+						writeln!(w, "\tlet {}_items: Vec<{}> = {}_str.split_whitespace().map(|s| s.parse().unwrap()).collect();", name, get_type(item_typ), name)?;
+					}
+					writeln!(w, "\tlet {}: {} = std::array::from_fn(|i| {}_items[i]);", name, get_type(typ), name)?;
+				},
+				_ => writeln!(w, "\tlet {} = {}_str.trim().parse::<{}>().unwrap();", name, name, get_type(typ))?,
 			}
-			write!(w, "\n")?;
+			writeln!(w)?;
 		}
 
 		let argv = n.args_in.iter().map(|(name, _)| {
@@ -426,23 +970,25 @@ pub fn format(w: &mut Write, f: &[Node]) -> Result<()> {
 		let call = Expr::Call{
 			name: n.name.clone(),
 			args: argv,
+			reset: None,
 		};
 
 		// Initialize the callee memory
 		if let Some(call_mem) = mems.get(&n.name) {
-			write!(w, "\tlet mut mem: {} = Default::default();\n", &call_mem.name)?;
+			writeln!(w, "\tlet mut mem: {} = Default::default();", &call_mem.name)?;
 		}
 
 		// Call the node in a loop
-		write!(w, "\tfor _ in 0..{} {{\n", num_calls)?;
+		writeln!(w, "\tfor _ in 0..{} {{", num_calls)?;
 
 		write!(w, "\t\tlet v = ")?;
-		format_expr(w, &call, &vec![], &mems)?;
-		write!(w, ";\n")?;
+		format_expr(w, &call, &[], n, &mems)?;
+		writeln!(w, ";")?;
 
-		write!(w, "\t\teprintln!(\"{{:?}}\", &v);\n")?;
+		writeln!(w, "\t\teprintln!(\"{{:?}}\", &v);")?;
 
-		write!(w, "\t}}\n")?;
+		writeln!(w, "\t}}")?;
 	}
-	write!(w, "}}\n")
+	writeln!(w, "}}")?;
+	Ok(())
 }
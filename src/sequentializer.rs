@@ -4,34 +4,54 @@
 //
 // 1. Generating the direct dependencies for each equation (find_dep_XXX)
 //    This is done by adding all the Ident in the left side of each equations to the dependencies
-//    We represent the graph using a HashMap<String, Vec<String>>
-//      keys: Varname
-//      values: List of var that the key depends on (children in a Graph)
+//    Equations computed on a sub-clock also depend on the variables of their clock
+//    We represent the graph by the index of the equations: for each equation, the equations
+//    defining the variables it reads, along with the name of these variables. Inputs and the
+//    variables read through `fby` and `pre` are not dependencies.
 //
-// 2. Propagating the dependencies (propagate)
-//    We explore the children of each variable and add their own dependecies to the current Variable
-//    We use a queue of what remains to be explored and keep track of what variable we already visited
-//    so as to not loop endlessly.
+// 2. Re-ordering using the dependencies (schedule)
+//    This is a topological sort (Kahn's algorithm): an equation is ready once all the equations
+//    it depends on are placed in the body. Among the ready equations, the first one in the source
+//    is placed first, so that the generated code is always the same.
+//    This takes O(E log E) time for E equations and dependencies.
 //
 // 3. Checking the satisfiability of the ordering
 //    The only way we could not be able to order the equations is the circular dependency
-//    We can detect those easily by finding the cycles in the graph.
-//    Thanks to propagation we just need to check that no variable depends on itself to be computed.
+//    The equations which are never ready are on a cycle, or depend on one.
+//    A call on a cycle is split if the callee has outputs which can be read before it is stepped:
+//    each group of these outputs is read once the arguments it depends on are known, and the
+//    callee is stepped once all its arguments are known. The equations are then ordered again.
+//    For instance `o = integr(o)`, where `o` only depends on the memory of `integr`, becomes:
 //
-// 4. Re-ordering using the dependencies (order)
-//    We construct the Node's body incrementally
-//    Each loop turn we check wether or not all the dependecies of each equation has been met.
-//    If it has we can append this equation to the body
-//    We repeat this until all the equations are placed in the body.
-
-use std::collections::HashMap;
-use std::collections::VecDeque;
+//    ```rust
+//    let o = integr_outputs_0(&mem.o);
+//    let _ = integr(&mut mem.o, o);
+//    ```
+//
+//    Each remaining cycle is reported once, as the shortest path of direct dependencies from one
+//    of its variables back to itself, where intermediates are shown as the sub-expression they
+//    stand for.
+//
+// 4. Finding the inputs each output depends on in the same instant (find_node_io)
+//    This follows the dependencies of the variables, and for calls only the arguments the output
+//    of the callee depends on. Outputs can be read before the node is stepped unless computing
+//    them requires stepping a node or resetting its memory. They are grouped by the inputs they
+//    depend on, and each group is computed by the equations it depends on, where calls are
+//    replaced with reading the groups of the callee. Groups which don't depend on any input are
+//    Moore outputs.
+//
+// Nodes are scheduled after the nodes they call, so that the groups of outputs of the callees are
+// known. Calls to recursive nodes and external nodes are never split.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use crate::nast::*;
+use crate::diagnostic::{CompileError, Diagnostic, collect};
 
 fn find_dep_atom(a: &Atom) -> Vec<String> {
 	match a {
 		Atom::Ident(s) => vec!{s.to_string()},
-		Atom::Const(_) => vec!{},
+		Atom::Const(_) | Atom::Global(_) => vec!{},
 	}
 }
 
@@ -39,13 +59,13 @@ fn find_dep_bexpr(e: &Bexpr) -> Vec<String> {
 	match e {
 		Bexpr::Unop(_, e) => find_dep_bexpr(e),
 		Bexpr::Binop(_, exprs) => {
-			let (e1, e2): &(Bexpr, Bexpr) = &*exprs;
+			let (e1, e2): &(Bexpr, Bexpr) = exprs;
 			let mut v1 = find_dep_bexpr(e1);
 			v1.append(&mut find_dep_bexpr(e2));
 			v1
 		},
 		Bexpr::If(exprs) => {
-			let (e1, e2, e3): &(Bexpr, Bexpr, Bexpr) = &*exprs;
+			let (e1, e2, e3): &(Bexpr, Bexpr, Bexpr) = exprs;
 			let mut v1 = find_dep_bexpr(e1);
 			v1.append(&mut find_dep_bexpr(e2));
 			v1.append(&mut find_dep_bexpr(e3));
@@ -55,134 +75,512 @@ fn find_dep_bexpr(e: &Bexpr) -> Vec<String> {
 			let v = vexpr.iter().map(find_dep_bexpr);
 			v.into_iter().flatten().collect()
 		},
+		Bexpr::When(e, clock, _) => {
+			let mut v = find_dep_bexpr(e);
+			v.push(clock.to_string());
+			v
+		},
+		Bexpr::Merge(clock, cases) => {
+			let v = cases.iter().map(|(_, e)| find_dep_bexpr(e));
+			let mut v: Vec<String> = v.into_iter().flatten().collect();
+			v.push(clock.to_string());
+			v
+		},
+		Bexpr::Array(vexpr) => {
+			let v = vexpr.iter().map(find_dep_bexpr);
+			v.into_iter().flatten().collect()
+		},
+		Bexpr::Repeat(e, _) => find_dep_bexpr(e),
+		Bexpr::Index(a, i) => {
+			let mut v = find_dep_atom(a);
+			v.append(&mut find_dep_bexpr(i));
+			v
+		},
+		Bexpr::Record(_, values) => {
+			let v = values.iter().map(|(_, e)| find_dep_bexpr(e));
+			v.into_iter().flatten().collect()
+		},
+		Bexpr::Field(e, _) => find_dep_bexpr(e),
 		Bexpr::Atom(a) => find_dep_atom(a),
 	}
 }
 
+// Finds the clock variables needed to know whether a clock is active
+fn find_dep_clock(c: &Clock) -> Vec<String> {
+	match c {
+		Clock::Base => vec!{},
+		Clock::On(parent, clock, _) => {
+			let mut v = find_dep_clock(parent);
+			v.push(clock.to_string());
+			v
+		},
+	}
+}
+
 // Finds the direct dependencies to compute the equation
 fn find_dep_eq(e: &Equation) -> Vec<String> {
+	let mut v = find_dep_clock(&e.clock);
+	v.append(&mut find_dep_expr(&e.body));
+	v.append(&mut find_dep_outputs(e));
+	v
+}
+
+// Finds the outputs of a split call which are read before the step, so that the memory of the
+// callee is read before it is updated
+fn find_dep_outputs(e: &Equation) -> Vec<String> {
 	match &e.body {
-		Expr::Bexpr(be) => find_dep_bexpr(&be),
-		Expr::Call{name:_, args} => {
+		Expr::Step{call, ..} => call.iter().filter(|name| !e.names.contains(name)).cloned().collect(),
+		_ => vec!{},
+	}
+}
+
+fn find_dep_expr(e: &Expr) -> Vec<String> {
+	match e {
+		Expr::Bexpr(be) => find_dep_bexpr(be),
+		Expr::Call{name:_, args, reset} => {
 			let v = args.iter().map(find_dep_bexpr);
+			let mut v: Vec<String> = v.into_iter().flatten().collect();
+			if let Some(cond) = reset {
+				v.append(&mut find_dep_atom(cond));
+			}
+			v
+		},
+		Expr::Iterate{args, ..} => {
+			let v = args.iter().map(find_dep_atom);
 			v.into_iter().flatten().collect()
 		},
-		Expr::Fby(vexpr1, vexpr2) => {
+		Expr::Fby(vexpr1, _) => {
+			// The initial values may be split from a tuple literal while the next value is a single
+			// tuple (see `normalizer.rs`)
+			let v = vexpr1.iter().map(find_dep_atom);
+			v.into_iter().flatten().collect()
+		},
+		// The previous value is read from memory, no dependency
+		Expr::Pre(_) => vec!{},
+		Expr::Current(a) => find_dep_atom(a),
+		// The outputs also depend on the memory, which is reset first
+		Expr::Outputs{args, reset, ..} => {
+			let mut v: Vec<String> = args.iter().flat_map(find_dep_bexpr).collect();
+			v.extend(reset.iter().flat_map(find_dep_atom));
+			v
+		},
+		Expr::Step{args, ..} => {
+			let v = args.iter().map(find_dep_bexpr);
+			v.into_iter().flatten().collect()
+		},
+		Expr::Arrow(vexpr1, vexpr2) => {
+			// Both operands have the same type (see `typer.rs`), and the normalization only splits
+			// them when they are both tuple literals
 			if vexpr1.len() != vexpr2.len() {
-				panic!("Expected same tuple size on fby")
+				unreachable!("mismatched tuples in ->")
 			}
-			let v = vexpr1.iter().map(find_dep_atom);
+			let v = vexpr1.iter().chain(vexpr2.iter()).map(find_dep_bexpr);
 			v.into_iter().flatten().collect()
 		},
 	}
 }
 
-// propagates the dependencies for each equation
-fn propagate(deps: &HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
-	let mut finaldeps = HashMap::new();
+/// For each equation of a node, the equations it depends on along with the variable read.
+type Deps = Vec<Vec<(usize, String)>>;
 
-	for (key, values) in deps {
-		let mut todo = VecDeque::from(values.clone());
-		let mut alldeps = vec!{};
+// finds the equations each equation depends on, and reports variables defined twice
+fn find_deps(n: &Node, errors: &mut Vec<CompileError>) -> Deps {
+	let mut defs: HashMap<&str, usize> = HashMap::new();
+	for (i, eq) in n.body.iter().enumerate() {
+		for name in &eq.names {
+			if defs.contains_key(name.as_str()) {
+				let msg = format!("two equations define `{}` in node `{}`", name, &n.name);
+				errors.push(CompileError::Definition(Diagnostic::new(&eq.span, msg)));
+				continue;
+			}
+			defs.insert(name, i);
+		}
+	}
+	n.body.iter().map(|eq| {
+		let mut deps: Vec<(usize, String)> = find_dep_eq(eq).into_iter().filter_map(|name| {
+			defs.get(name.as_str()).map(|i| (*i, name))
+		}).collect();
+		deps.sort();
+		deps.dedup();
+		deps
+	}).collect()
+}
 
-		// while the queue [todo] is not empty
-		while !todo.is_empty() {
-			let d = todo.pop_front().unwrap();
-			if deps.contains_key(&d) {
-				let values = deps.get(&d).unwrap();
-				for dnext in values {
-					// don't add if already done or to be done
-					if !alldeps.contains(dnext) && !todo.contains(dnext) {
-						todo.push_back(dnext.to_string()); // add the dependecies
-					}
+// returns the indices of the equations in a valid order, equations on a cycle are left out
+fn schedule(deps: &Deps) -> Vec<usize> {
+	let mut users = vec![Vec::new(); deps.len()];
+	for (i, eq_deps) in deps.iter().enumerate() {
+		for (j, _) in eq_deps {
+			users[*j].push(i);
+		}
+	}
+	let mut missing: Vec<usize> = deps.iter().map(|eq_deps| eq_deps.len()).collect();
+	let mut ready: BinaryHeap<Reverse<usize>> = (0..deps.len()).filter(|i| missing[*i] == 0).map(Reverse).collect();
+	let mut order = Vec::new();
+	while let Some(Reverse(i)) = ready.pop() {
+		order.push(i);
+		for u in &users[i] {
+			missing[*u] -= 1;
+			if missing[*u] == 0 {
+				ready.push(Reverse(*u));
+			}
+		}
+	}
+	order
+}
+
+// finds the shortest path of direct dependencies from an equation back to itself, as the
+// variables read along the way, starting and ending with the variable of the equation
+fn find_cycle(deps: &Deps, start: usize) -> Option<Vec<&str>> {
+	let mut parents: HashMap<usize, (usize, &str)> = HashMap::new();
+	let mut todo = VecDeque::from(vec![start]);
+	while let Some(i) = todo.pop_front() {
+		for (j, name) in &deps[i] {
+			if *j == start {
+				// walk back from the last equation of the cycle
+				let mut path = vec![name.as_str()];
+				let mut k = i;
+				while k != start {
+					let (parent, name) = parents[&k];
+					path.push(name);
+					k = parent;
 				}
+				path.reverse();
+				path.insert(0, name);
+				return Some(path);
 			}
-			// adds the current value as done
-			if !alldeps.contains(&d) {
-				alldeps.push(d);
+			if !parents.contains_key(j) {
+				parents.insert(*j, (i, name));
+				todo.push_back(*j);
 			}
 		}
-		finaldeps.insert(key.to_string(), alldeps);
 	}
-	finaldeps
+	None
 }
 
-// orders the equations using the dependency graph
-fn order(n: &Node, mut alldeps: HashMap<String, Vec<String>>) -> Vec<Equation> {
-	let mut orderedEqs = Vec::new();
-
-	while !alldeps.is_empty() {
-		let mut remove = Vec::new();
+fn dep_edges(deps: &Deps) -> Vec<Vec<usize>> {
+	deps.iter().map(|eq_deps| eq_deps.iter().map(|(j, _)| *j).collect()).collect()
+}
 
-		for (var, deps) in &alldeps {
-			let mut ok = true;
-			// Compute: if the dependecies have been met by previously added equations and inputs
-			for dep in deps {
-				let isPrevEq = orderedEqs.iter().fold(false, |r, val: &Equation| {
-					r || val.names.contains(&dep)
-				});
-				ok = ok && (n.args_in.contains_key(dep) || isPrevEq);
-			}
-			if ok { // if dependencies satisfied
-				// we put the corresponding equation as the next one to be computed
-				let eq = n.body.iter().find(|&eq1| eq1.names.contains(&var)).unwrap();
-				orderedEqs.push(eq.clone());
-
-				for (k, _) in &alldeps {
-					if eq.names.contains(k) {
-						remove.push(k.clone());
-					}
-				}
+// finds the equations reachable from an equation, following the dependencies or the users
+fn reachable(edges: &[Vec<usize>], start: usize) -> HashSet<usize> {
+	let mut seen = HashSet::new();
+	let mut todo = vec![start];
+	while let Some(i) = todo.pop() {
+		for j in &edges[i] {
+			if seen.insert(*j) {
+				todo.push(*j);
 			}
 		}
+	}
+	seen
+}
+
+fn is_intermediate(eq: &Equation, name: &str) -> bool {
+	eq.intermediate && eq.names.first().is_some_and(|first| first == name)
+}
+
+// describes a variable in an error message, intermediates are shown as their source code
+fn describe(n: &Node, name: &str) -> String {
+	match n.body.iter().find(|eq| is_intermediate(eq, name)) {
+		Some(eq) => format!("`{}`", eq.span.as_str().split_whitespace().collect::<Vec<&str>>().join(" ")),
+		None => format!("`{}`", name),
+	}
+}
+
+// reports a cycle of dependencies
+fn cycle_error(n: &Node, path: &[&str], span: &Span) -> Diagnostic {
+	let is_intermediate = |name: &str| n.body.iter().any(|eq| is_intermediate(eq, name));
+	let described: Vec<String> = path.iter().map(|name| describe(n, name)).collect();
+	let msg = format!("circular dependency in node `{}`: {}", &n.name, described.join(" -> "));
 
-		// removing variables that are also computed by the equation (in tuples)
-		// this works because all variables assigned in a tuple all have the same dependecies
-		for k in &remove {
-			alldeps.remove(k);
+	// the cycle is broken by reading the previous value of a variable of the source code
+	let (reader, dep) = path.iter().zip(path.iter().skip(1)).find(|(_, dep)| !is_intermediate(dep)).unwrap();
+	let reader = match is_intermediate(reader) {
+		true => describe(n, reader),
+		false => format!("the definition of `{}`", reader),
+	};
+	let help = format!("help: use the previous value of `{}` in {} to break the cycle, with `pre {}` or `... fby {}`", dep, reader, dep, dep);
+	Diagnostic::new(span, msg).with_note(help)
+}
+
+// reports each cycle among the equations which cannot be scheduled once, at its first equation,
+// which is the sub-expression it computes for an intermediate (see `nast.rs`)
+fn cycle_errors(n: &Node, deps: &Deps, order: &[usize], errors: &mut Vec<CompileError>) {
+	let scheduled: HashSet<usize> = order.iter().cloned().collect();
+	let dep_edges = dep_edges(deps);
+	let mut user_edges = vec![Vec::new(); deps.len()];
+	for (i, eq_deps) in dep_edges.iter().enumerate() {
+		for j in eq_deps {
+			user_edges[*j].push(i);
+		}
+	}
+	let mut reported = HashSet::new();
+	for i in (0..deps.len()).filter(|i| !scheduled.contains(i)) {
+		if reported.contains(&i) {
+			continue;
+		}
+		// equations which only depend on a cycle are not on one
+		if let Some(path) = find_cycle(deps, i) {
+			errors.push(CompileError::Causality(cycle_error(n, &path, &n.body[i].span)));
+			let after = reachable(&user_edges, i);
+			reported.extend(reachable(&dep_edges, i).into_iter().filter(|j| after.contains(j)));
 		}
 	}
-	orderedEqs
 }
 
-fn sequentialize_node(n: &Node) -> Node {
-	// Create dependency graph
-	let mut deps: HashMap<String, Vec<String>> = HashMap::new();
-	for eq in &n.body {
-		let dep = find_dep_eq(&eq);
-		for name in &eq.names {
-			if deps.contains_key(name) {
-				panic!("Two equations define `{}` in node `{}`", name, &n.name)
-			}
-			deps.insert(name.clone(), dep.clone());
+/// Instantaneous dependencies of the outputs of a node on its inputs.
+struct Io {
+	n_inputs: usize,
+	/// Indices of the inputs each output depends on
+	deps: Vec<Vec<usize>>,
+	/// Groups of outputs which can be read before the node is stepped, which depend on the same
+	/// inputs
+	groups: Vec<Vec<usize>>,
+}
+
+// finds the io of the callee of a call whose variables are `call`, if it is known
+fn find_io<'a>(ios: &'a HashMap<String, Io>, name: &str, args: &[Bexpr], call: &[String]) -> Option<&'a Io> {
+	ios.get(name).filter(|io| io.n_inputs == args.len() && io.deps.len() == call.len())
+}
+
+// splits the calls on a cycle whose callee has outputs which can be read before it is stepped,
+// returns None if there is none
+fn split_calls(n: &Node, deps: &Deps, order: &[usize], ios: &HashMap<String, Io>) -> Option<Vec<Equation>> {
+	let scheduled: HashSet<usize> = order.iter().cloned().collect();
+	let dep_edges = dep_edges(deps);
+	let mut body = Vec::new();
+	let mut split = false;
+	for (i, eq) in n.body.iter().enumerate() {
+		let (name, args, reset) = match &eq.body {
+			Expr::Call{name, args, reset} if !scheduled.contains(&i) => (name, args, reset),
+			_ => {
+				body.push(eq.clone());
+				continue;
+			},
+		};
+		let io = match find_io(ios, name, args, &eq.names) {
+			Some(io) if !io.groups.is_empty() && reachable(&dep_edges, i).contains(&i) => io,
+			_ => {
+				body.push(eq.clone());
+				continue;
+			},
+		};
+		let part = |names: Vec<String>, body: Expr| Equation{
+			names,
+			body,
+			clock: eq.clock.clone(),
+			span: eq.span.clone(),
+			intermediate: eq.intermediate,
+		};
+		// Each group is read separately, so that it only waits for the arguments it depends on.
+		// Resetting the memory again before reading the next group doesn't change it.
+		for (g, outputs) in io.groups.iter().enumerate() {
+			body.push(part(outputs.iter().map(|k| eq.names[*k].clone()).collect(), Expr::Outputs{
+				name: name.clone(),
+				call: eq.names.clone(),
+				group: g,
+				outputs: outputs.clone(),
+				args: io.deps[outputs[0]].iter().map(|j| args[*j].clone()).collect(),
+				reset: reset.clone(),
+			}));
 		}
+		let others = eq.names.iter().enumerate().filter(|(k, _)| !io.groups.iter().any(|outputs| outputs.contains(k)));
+		body.push(part(others.map(|(_, name)| name.clone()).collect(), Expr::Step{
+			name: name.clone(),
+			args: args.clone(),
+			call: eq.names.clone(),
+		}));
+		split = true;
+	}
+	if split { Some(body) } else { None }
+}
+
+// finds the variables a variable depends on in the same instant. For calls, only the arguments
+// of the inputs the output of the callee depends on are kept.
+fn find_dep_var(eq: &Equation, name: &str, ios: &HashMap<String, Io>) -> Vec<String> {
+	let (callee, args, call) = match &eq.body {
+		Expr::Call{name, args, ..} => (name, args, &eq.names),
+		Expr::Step{name, args, call} => (name, args, call),
+		_ => return find_dep_eq(eq),
+	};
+	let (io, k) = match (find_io(ios, callee, args, call), call.iter().position(|v| v == name)) {
+		(Some(io), Some(k)) => (io, k),
+		_ => return find_dep_eq(eq),
+	};
+	let mut v = find_dep_clock(&eq.clock);
+	if let Expr::Call{reset: Some(cond), ..} = &eq.body {
+		v.append(&mut find_dep_atom(cond));
+	}
+	v.append(&mut find_dep_outputs(eq));
+	for j in &io.deps[k] {
+		v.append(&mut find_dep_bexpr(&args[*j]));
+	}
+	v
+}
+
+// whether a variable can be computed from the memory of the node before it is stepped, provided
+// that the variables it depends on can
+fn is_early(eq: &Equation, name: &str, ios: &HashMap<String, Io>) -> bool {
+	match &eq.body {
+		Expr::Bexpr(_) | Expr::Fby(_, _) | Expr::Pre(_) | Expr::Arrow(_, _) | Expr::Current(_) => true,
+		Expr::Outputs{reset, ..} => reset.is_none(),
+		Expr::Call{name: callee, args, reset: None} => match find_io(ios, callee, args, &eq.names) {
+			Some(io) => eq.names.iter().position(|v| v == name).is_some_and(|k| io.groups.iter().any(|outputs| outputs.contains(&k))),
+			None => false,
+		},
+		_ => false,
 	}
+}
 
-	let alldeps = propagate(&deps);
+// finds the equations computing the variables `vars` before the node is stepped, where calls are
+// replaced with reading the groups of outputs they need
+fn find_early_body(n: &Node, vars: &HashSet<String>, ios: &HashMap<String, Io>) -> Vec<Equation> {
+	let mut body = Vec::new();
+	for eq in n.body.iter().filter(|eq| eq.names.iter().any(|name| vars.contains(name))) {
+		let (name, args, reset) = match &eq.body {
+			Expr::Call{name, args, reset} => (name, args, reset),
+			_ => {
+				body.push(eq.clone());
+				continue;
+			},
+		};
+		let io = find_io(ios, name, args, &eq.names).unwrap();
+		for (g, outputs) in io.groups.iter().enumerate() {
+			let names: Vec<String> = outputs.iter().map(|k| eq.names[*k].clone()).filter(|name| vars.contains(name)).collect();
+			if names.is_empty() {
+				continue;
+			}
+			body.push(Equation{
+				names,
+				body: Expr::Outputs{
+					name: name.clone(),
+					call: eq.names.clone(),
+					group: g,
+					outputs: outputs.clone(),
+					args: io.deps[outputs[0]].iter().map(|j| args[*j].clone()).collect(),
+					reset: reset.clone(),
+				},
+				clock: eq.clock.clone(),
+				span: eq.span.clone(),
+				intermediate: eq.intermediate,
+			});
+		}
+	}
+	body
+}
 
-	eprintln!("Depedencies for Node [{}]", n.name);
-	for (k, v) in &alldeps {
-		eprintln!("{} -> {:?}", k, v)
+// finds the inputs each output of a scheduled node depends on, and the groups of outputs which
+// can be read before it is stepped along with the equations computing them
+fn find_node_io(n: &Node, ios: &HashMap<String, Io>) -> (Io, Vec<OutputGroup>) {
+	let defs: HashMap<&str, &Equation> = n.body.iter().flat_map(|eq| eq.names.iter().map(move |name| (name.as_str(), eq))).collect();
+	let is_input = |name: &str| n.args_in.iter().any(|(input, _)| input == name);
+	let mut deps = Vec::new();
+	let mut groups: Vec<(OutputGroup, HashSet<String>)> = Vec::new();
+	for (k, (out, _)) in n.args_out.iter().enumerate() {
+		let mut seen: HashSet<String> = vec![out.clone()].into_iter().collect();
+		let mut todo = vec![out.clone()];
+		while let Some(name) = todo.pop() {
+			if let Some(eq) = defs.get(name.as_str()) {
+				for dep in find_dep_var(eq, &name, ios) {
+					if seen.insert(dep.clone()) {
+						todo.push(dep);
+					}
+				}
+			}
+		}
+		let out_deps: Vec<usize> = n.args_in.iter().enumerate().filter(|(_, (name, _))| seen.contains(name)).map(|(j, _)| j).collect();
+		if seen.iter().all(|name| is_input(name) || defs.get(name.as_str()).is_some_and(|eq| is_early(eq, name, ios))) {
+			match groups.iter_mut().find(|(group, _)| group.inputs == out_deps) {
+				Some((group, vars)) => {
+					group.outputs.push(k);
+					vars.extend(seen);
+				},
+				None => groups.push((OutputGroup{inputs: out_deps.clone(), outputs: vec![k], body: Vec::new()}, seen)),
+			}
+		}
+		deps.push(out_deps);
 	}
 
-	// Check if there is a solution to the ordering problem
-	for (key, deps) in &alldeps {
-		if deps.contains(key) {
-			panic!("Circular dependency detected in node `{}` for `{}` -> {:?}", &n.name, key, deps)
+	let groups: Vec<OutputGroup> = groups.into_iter().map(|(group, vars)| OutputGroup{
+		body: find_early_body(n, &vars, ios),
+		..group
+	}).collect();
+	let outputs = groups.iter().map(|group| group.outputs.clone()).collect();
+	(Io{n_inputs: n.args_in.len(), deps, groups: outputs}, groups)
+}
+
+fn sequentialize_node(n: &Node, ios: &HashMap<String, Io>) -> Result<(Node, Io), CompileError> {
+	let mut n = n.clone();
+	let mut errors = Vec::new();
+	let mut deps = find_deps(&n, &mut errors);
+	let mut order = schedule(&deps);
+	if order.len() < n.body.len() {
+		if let Some(body) = split_calls(&n, &deps, &order, ios) {
+			n.body = body;
+			deps = find_deps(&n, &mut Vec::new());
+			order = schedule(&deps);
 		}
 	}
+	if order.len() < n.body.len() {
+		cycle_errors(&n, &deps, &order, &mut errors);
+	}
+	collect(errors)?;
+
+	n.body = order.into_iter().map(|i| n.body[i].clone()).collect();
+	let (io, groups) = find_node_io(&n, ios);
+	n.groups = groups;
+	Ok((n, io))
+}
 
-	let orderedBody = order(&n, alldeps);
+// visits the nodes called by a node before the node itself
+fn visit_callees(f: &File, i: usize, visited: &mut Vec<bool>, order: &mut Vec<usize>) {
+	visited[i] = true;
+	for eq in &f.nodes[i].body {
+		if let Expr::Call{name, ..} = &eq.body {
+			if let Some(j) = f.nodes.iter().position(|n| &n.name == name) {
+				if !visited[j] {
+					visit_callees(f, j, visited, order);
+				}
+			}
+		}
+	}
+	order.push(i);
+}
 
-	Node{
-		name: n.name.clone(),
-		args_in: n.args_in.clone(),
-		args_out: n.args_out.clone(),
-		locals: n.locals.clone(),
-		body: orderedBody,
+/// Returns the indices of the nodes of a file, where the nodes called by a node come before it,
+/// except for recursive calls.
+pub fn callees_first(f: &File) -> Vec<usize> {
+	let mut visited = vec![false; f.nodes.len()];
+	let mut order = Vec::new();
+	for i in 0..f.nodes.len() {
+		if !visited[i] {
+			visit_callees(f, i, &mut visited, &mut order);
+		}
 	}
+	order
 }
 
-pub fn sequentialize(f: &[Node]) -> Vec<Node> {
-	f.iter().map(sequentialize_node).collect()
+/// Orders the equations of each node. Nodes are scheduled after the nodes they call, so that the
+/// dependencies of their outputs are known. The errors of all the nodes are reported.
+pub fn sequentialize(f: &File) -> Result<File, CompileError> {
+	let mut ios = HashMap::new();
+	let mut nodes = vec![None; f.nodes.len()];
+	let mut errors = Vec::new();
+	for i in callees_first(f) {
+		match sequentialize_node(&f.nodes[i], &ios) {
+			Ok((n, io)) => {
+				ios.insert(n.name.clone(), io);
+				nodes[i] = Some(n);
+			},
+			Err(err) => errors.push(err),
+		}
+	}
+	collect(errors)?;
+	Ok(File{
+		types: f.types.clone(),
+		consts: f.consts.clone(),
+		externs: f.externs.clone(),
+		nodes: nodes.into_iter().map(Option::unwrap).collect(),
+	})
 }
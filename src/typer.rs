@@ -1,6 +1,427 @@
+// Type checking
+//
+// The type checker walks the raw AST of each node and checks that:
+//
+// - Each variable is declared once, and each identifier is declared in the inputs, outputs or
+//   local variables of the node
+// - Each call refers to a node or an external declaration, with the right number of arguments and
+//   argument types
+// - Operators are applied to operands of the right type: non-dot operators to integers and dot
+//   operators to floats
+// - Both sides of each equation have the same type, in particular tuples have the same arity
+// - Array items have the same type, and constant indices and slices are within bounds
+// - Iterated nodes take (and return, for `fold` and `red`) an accumulator followed by items of
+//   the array arguments
+// - Records are built with a value for each of their fields, and only declared fields are accessed
+// - `merge` has exactly one case for each value of its boolean or enumeration clock variable
+// - Constants have the declared type, and variables don't have the name of a constant
+
+use std::collections::HashMap;
+use crate::ast::{self, ExprKind};
+use crate::diagnostic::{CompileError, Diagnostic, collect, error};
+use crate::evaluator::eval;
 use crate::nast::*;
 
-// TODO: this doesn't perform type checking
+/// Input and output types of a node.
+pub struct Signature {
+	pub args_in: Vec<Type>,
+	pub args_out: Vec<Type>,
+}
+
+/// Declarations which are visible from all nodes.
+pub struct Globals {
+	pub sigs: HashMap<String, Signature>,
+	pub types: HashMap<String, TypeDef>,
+	pub consts: HashMap<String, Type>,
+	/// Value of each constant which can be evaluated, to check constant array indices
+	pub values: HashMap<String, Const>,
+}
+
+pub fn format_type(t: &Type) -> String {
+	match t {
+		Type::Unit => "unit".to_string(),
+		Type::Bool => "bool".to_string(),
+		Type::Int => "int".to_string(),
+		Type::Float => "float".to_string(),
+		Type::String => "string".to_string(),
+		Type::Tuple(types) => {
+			let types: Vec<String> = types.iter().map(format_type).collect();
+			format!("({})", types.join(", "))
+		},
+		Type::Array(t, size) => format!("{}^{}", format_type(t), size),
+		Type::Record(name) | Type::Enum(name) => name.clone(),
+	}
+}
+
+struct Context<'a> {
+	/// Node whose body is checked, if any
+	node: Option<&'a ast::Node>,
+	globals: &'a Globals,
+}
+
+impl<'a> Context<'a> {
+	fn new(n: &'a ast::Node, globals: &'a Globals) -> Self {
+		Context{node: Some(n), globals}
+	}
+
+	fn var(&self, name: &str, span: &Span) -> Result<Type, CompileError> {
+		let var = self.node.and_then(|n| {
+			let mut vars = n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter());
+			vars.find(|(var, _)| var == name)
+		});
+		match var {
+			Some((_, t)) => Ok(t.clone()),
+			None => error(CompileError::UnknownName, span, format!("unknown variable `{}`", name)),
+		}
+	}
+
+	fn expect(&self, expected: &Type, actual: &Type, what: &str, span: &Span) -> Result<(), CompileError> {
+		if expected != actual {
+			return error(CompileError::Type, span, format!("expected {} for {}, found {}", format_type(expected), what, format_type(actual)));
+		}
+		Ok(())
+	}
+
+	fn expect_same(&self, t1: &Type, t2: &Type, what: &str, span: &Span) -> Result<(), CompileError> {
+		if t1 != t2 {
+			return error(CompileError::Type, span, format!("{} have different types ({} and {})", what, format_type(t1), format_type(t2)));
+		}
+		Ok(())
+	}
+
+	fn sig(&self, name: &str, span: &Span) -> Result<&Signature, CompileError> {
+		match self.globals.sigs.get(name) {
+			Some(sig) => Ok(sig),
+			None => error(CompileError::UnknownName, span, format!("unknown node `{}`", name)),
+		}
+	}
+
+	fn record_fields(&self, name: &str, span: &Span) -> Result<&[(String, Type)], CompileError> {
+		match self.globals.types.get(name) {
+			Some(TypeDef::Record(fields)) => Ok(fields),
+			_ => error(CompileError::UnknownName, span, format!("unknown record `{}`", name)),
+		}
+	}
+
+	/// Returns all the values of the type of a clock variable.
+	fn clock_values(&self, clock: &str, span: &Span) -> Result<Vec<Const>, CompileError> {
+		match self.var(clock, span)? {
+			Type::Bool => Ok(vec![Const::Bool(true), Const::Bool(false)]),
+			Type::Enum(name) => match &self.globals.types[&name] {
+				TypeDef::Enum(variants) => Ok(variants.iter().map(|v| Const::Enum(name.clone(), v.clone())).collect()),
+				_ => unreachable!(),
+			},
+			t => error(CompileError::Type, span, format!("expected a boolean or an enumeration for clock `{}`, found {}", clock, format_type(&t))),
+		}
+	}
+
+	fn type_of_record(&self, name: &str, values: &[(String, ast::Expr)], span: &Span) -> Result<Type, CompileError> {
+		let fields = self.record_fields(name, span)?;
+		for (field, e) in values {
+			let t = match fields.iter().find(|(f, _)| f == field) {
+				Some((_, t)) => t,
+				None => return error(CompileError::UnknownName, &e.span, format!("record `{}` has no field `{}`", name, field)),
+			};
+			self.expect(t, &self.type_of(e)?, &format!("field `{}` of `{}`", field, name), &e.span)?;
+		}
+		for (field, _) in fields {
+			match values.iter().filter(|(f, _)| f == field).count() {
+				0 => return error(CompileError::Definition, span, format!("missing field `{}` of `{}`", field, name)),
+				1 => {},
+				_ => return error(CompileError::Definition, span, format!("field `{}` of `{}` is set twice", field, name)),
+			}
+		}
+		Ok(Type::Record(name.to_string()))
+	}
+
+	fn type_of_call(&self, name: &str, args: &[ast::Expr], span: &Span) -> Result<Type, CompileError> {
+		let sig = self.sig(name, span)?;
+		if args.len() != sig.args_in.len() {
+			return error(CompileError::Arity, span, format!("`{}` expects {} arguments, found {}", name, sig.args_in.len(), args.len()));
+		}
+		for (i, (arg, t)) in args.iter().zip(sig.args_in.iter()).enumerate() {
+			let arg_t = self.type_of(arg)?;
+			self.expect(t, &arg_t, &format!("argument #{} of `{}`", i + 1, name), &arg.span)?;
+		}
+		Ok(type_from_vec(sig.args_out.clone()))
+	}
+
+	fn array_item(&self, t: &Type, what: &str, span: &Span) -> Result<(Type, usize), CompileError> {
+		match t {
+			Type::Array(t, size) => Ok((*t.clone(), *size)),
+			_ => error(CompileError::Type, span, format!("expected an array for {}, found {}", what, format_type(t))),
+		}
+	}
+
+	fn type_of_iterate(&self, iter: Iter, name: &str, size: usize, args: &[ast::Expr], span: &Span) -> Result<Type, CompileError> {
+		let sig = self.sig(name, span)?;
+		if args.len() != sig.args_in.len() {
+			return error(CompileError::Arity, span, format!("`{}` expects {} arguments, found {}", name, sig.args_in.len(), args.len()));
+		}
+		// Fold and red pass an accumulator as first argument, along with items of arrays
+		let n_acc = match iter {
+			Iter::Map => 0,
+			Iter::Fold | Iter::Red => 1,
+		};
+		if n_acc > args.len() || n_acc > sig.args_out.len() {
+			return error(CompileError::Arity, span, format!("`{}` cannot be iterated with an accumulator", name));
+		}
+		for (i, (arg, t)) in args.iter().zip(sig.args_in.iter()).enumerate() {
+			let what = format!("argument #{} of `{}`", i + 1, name);
+			let arg_t = self.type_of(arg)?;
+			if i < n_acc {
+				self.expect(t, &arg_t, &what, &arg.span)?;
+				self.expect(t, &sig.args_out[i], &format!("accumulator of `{}`", name), span)?;
+			} else {
+				self.expect(&Type::Array(Box::new(t.clone()), size), &arg_t, &what, &arg.span)?;
+			}
+		}
+		let mut out = sig.args_out[..n_acc].to_vec();
+		if iter != Iter::Red {
+			out.extend(sig.args_out[n_acc..].iter().map(|t| Type::Array(Box::new(t.clone()), size)));
+		}
+		Ok(type_from_vec(out))
+	}
+
+	fn type_of_binop(&self, op: Binop, e1: &ast::Expr, e2: &ast::Expr, span: &Span) -> Result<Type, CompileError> {
+		let (t1, t2) = (&self.type_of(e1)?, &self.type_of(e2)?);
+		Ok(match op {
+			Binop::Plus | Binop::Minus | Binop::Mult | Binop::Div => {
+				self.expect(&Type::Int, t1, "left operand of an integer operator", &e1.span)?;
+				self.expect(&Type::Int, t2, "right operand of an integer operator", &e2.span)?;
+				Type::Int
+			},
+			Binop::PlusDot | Binop::MinusDot | Binop::MultDot | Binop::DivDot => {
+				self.expect(&Type::Float, t1, "left operand of a float operator", &e1.span)?;
+				self.expect(&Type::Float, t2, "right operand of a float operator", &e2.span)?;
+				Type::Float
+			},
+			Binop::Lt | Binop::Gt | Binop::Leq | Binop::Geq => {
+				self.expect_same(t1, t2, "operands of a comparison", span)?;
+				if *t1 != Type::Int && *t1 != Type::Float {
+					return error(CompileError::Type, span, format!("cannot compare values of type {}", format_type(t1)));
+				}
+				Type::Bool
+			},
+			Binop::Eq => {
+				self.expect_same(t1, t2, "operands of `=`", span)?;
+				Type::Bool
+			},
+			Binop::Neq => {
+				self.expect_same(t1, t2, "operands of `<>`", span)?;
+				Type::Bool
+			},
+			Binop::And | Binop::Or | Binop::Xor => {
+				self.expect(&Type::Bool, t1, "left operand of a boolean operator", &e1.span)?;
+				self.expect(&Type::Bool, t2, "right operand of a boolean operator", &e2.span)?;
+				Type::Bool
+			},
+		})
+	}
+
+	fn type_of(&self, e: &ast::Expr) -> Result<Type, CompileError> {
+		let span = &e.span;
+		Ok(match &e.kind {
+			ExprKind::Call{name, args, reset} => {
+				if let Some(cond) = reset {
+					self.expect(&Type::Bool, &self.type_of(cond)?, &format!("the reset condition of `{}`", name), &cond.span)?;
+				}
+				self.type_of_call(name, args, span)?
+			},
+			ExprKind::Iterate{iter, name, size, args} => self.type_of_iterate(*iter, name, *size, args, span)?,
+			ExprKind::Const(c) => type_of_const(c),
+			ExprKind::Unop(op, e) => {
+				let t = self.type_of(e)?;
+				let expected = match op {
+					Unop::Minus => Type::Int,
+					Unop::MinusDot => Type::Float,
+					Unop::Not => Type::Bool,
+				};
+				self.expect(&expected, &t, "operand of a unary operator", &e.span)?;
+				t
+			},
+			ExprKind::Binop(op, exprs) => {
+				let (e1, e2): &(ast::Expr, ast::Expr) = exprs;
+				self.type_of_binop(*op, e1, e2, span)?
+			},
+			ExprKind::Fby(exprs) | ExprKind::Arrow(exprs) => {
+				let (e1, e2): &(ast::Expr, ast::Expr) = exprs;
+				let t = self.type_of(e1)?;
+				self.expect_same(&t, &self.type_of(e2)?, "operands of a stream operator", span)?;
+				t
+			},
+			ExprKind::Pre(e) | ExprKind::Current(e) => self.type_of(e)?,
+			ExprKind::If(iff) => {
+				let (cond, body, else_part): &(ast::Expr, ast::Expr, ast::Expr) = iff;
+				self.expect(&Type::Bool, &self.type_of(cond)?, "condition of `if`", &cond.span)?;
+				let t = self.type_of(body)?;
+				self.expect_same(&t, &self.type_of(else_part)?, "branches of `if`", span)?;
+				t
+			},
+			ExprKind::When(e, clock, value) => {
+				self.expect(&type_of_const(value), &self.var(clock, span)?, &format!("clock `{}`", clock), span)?;
+				self.type_of(e)?
+			},
+			ExprKind::Merge(clock, cases) => {
+				let clock_t = self.var(clock, span)?;
+				let mut t = None;
+				for (value, e) in cases {
+					self.expect(&clock_t, &type_of_const(value), &format!("case of `{}`", clock), &e.span)?;
+					let case_t = self.type_of(e)?;
+					match &t {
+						Some(t) => self.expect_same(t, &case_t, "cases of `merge`", &e.span)?,
+						None => t = Some(case_t),
+					}
+				}
+				for value in self.clock_values(clock, span)? {
+					match cases.iter().filter(|(v, _)| *v == value).count() {
+						0 => return error(CompileError::Definition, span, format!("missing case {} in `merge` on `{}`", format_const(&value), clock)),
+						1 => {},
+						_ => return error(CompileError::Definition, span, format!("case {} appears twice in `merge` on `{}`", format_const(&value), clock)),
+					}
+				}
+				t.unwrap()
+			},
+			ExprKind::Ident(name) => self.var(name, span)?,
+			ExprKind::Global(name) => match self.globals.consts.get(name) {
+				Some(t) => t.clone(),
+				None => return error(CompileError::UnknownName, span, format!("unknown constant `{}`", name)),
+			},
+			ExprKind::Tuple(exprs) => Type::Tuple(exprs.iter().map(|e| self.type_of(e)).collect::<Result<_, _>>()?),
+			ExprKind::Array(exprs) => {
+				let t = self.type_of(&exprs[0])?;
+				for e in &exprs[1..] {
+					self.expect_same(&t, &self.type_of(e)?, "items of an array", &e.span)?;
+				}
+				Type::Array(Box::new(t), exprs.len())
+			},
+			ExprKind::Repeat(e, size) => Type::Array(Box::new(self.type_of(e)?), *size),
+			ExprKind::Index(index) => {
+				let (e, i): &(ast::Expr, ast::Expr) = index;
+				let (t, size) = self.array_item(&self.type_of(e)?, "indexed expression", &e.span)?;
+				self.expect(&Type::Int, &self.type_of(i)?, "array index", &i.span)?;
+				if let Ok(Const::Int(i)) = eval(i, &self.globals.values) {
+					if i < 0 || i as usize >= size {
+						return error(CompileError::Type, span, format!("index {} is out of bounds for an array of size {}", i, size));
+					}
+				}
+				t
+			},
+			ExprKind::Slice(e, start, end) => {
+				let (t, size) = self.array_item(&self.type_of(e)?, "sliced expression", &e.span)?;
+				if start > end || *end >= size {
+					return error(CompileError::Type, span, format!("slice {}..{} is out of bounds for an array of size {}", start, end, size));
+				}
+				Type::Array(Box::new(t), end - start + 1)
+			},
+			ExprKind::Record(name, values) => self.type_of_record(name, values, span)?,
+			ExprKind::Field(e, field) => {
+				let t = self.type_of(e)?;
+				let name = match &t {
+					Type::Record(name) => name,
+					_ => return error(CompileError::Type, span, format!("expected a record for field `{}`, found {}", field, format_type(&t))),
+				};
+				match self.record_fields(name, span)?.iter().find(|(f, _)| f == field) {
+					Some((_, t)) => t.clone(),
+					None => return error(CompileError::UnknownName, span, format!("record `{}` has no field `{}`", name, field)),
+				}
+			},
+		})
+	}
+
+	fn check_equation(&self, eq: &ast::Equation) -> Result<(), CompileError> {
+		let names_t = type_from_vec(eq.names.iter().map(|name| self.var(name, &eq.span)).collect::<Result<_, _>>()?);
+		let body_t = self.type_of(&eq.body)?;
+		if let (Type::Tuple(v1), Type::Tuple(v2)) = (&names_t, &body_t) {
+			if v1.len() != v2.len() {
+				return error(CompileError::Arity, &eq.span, format!("equation defines {} variables, but its body has {} values", v1.len(), v2.len()));
+			}
+		}
+		let what = format!("the definition of {}", eq.names.iter().map(|name| format!("`{}`", name)).collect::<Vec<String>>().join(", "));
+		self.expect(&names_t, &body_t, &what, &eq.span)
+	}
+}
+
+fn signature(args_in: &[(String, Type)], args_out: &[(String, Type)]) -> Signature {
+	Signature{
+		args_in: args_in.iter().map(|(_, t)| t.clone()).collect(),
+		args_out: args_out.iter().map(|(_, t)| t.clone()).collect(),
+	}
+}
+
+/// Collects the signatures of the external declarations and of the nodes, and the declared types.
+pub fn globals(f: &ast::File) -> Result<Globals, CompileError> {
+	let mut sigs = HashMap::new();
+	// Declaration of each node
+	let mut spans: HashMap<&str, &Span> = HashMap::new();
+	let mut errors = Vec::new();
+	let decls = f.externs.iter().map(|decl| (&decl.name, &decl.span, signature(&decl.args_in, &decl.args_out)));
+	let nodes = f.nodes.iter().map(|n| (&n.name, &n.span, signature(&n.args_in, &n.args_out)));
+	for (name, span, sig) in decls.chain(nodes) {
+		if let Some(other) = spans.get(name.as_str()) {
+			let msg = format!("node `{}` is defined twice (first at {:?})", name, other);
+			errors.push(CompileError::Definition(Diagnostic::new(span, msg)));
+			continue;
+		}
+		spans.insert(name, span);
+		sigs.insert(name.clone(), sig);
+	}
+	collect(errors)?;
+	let types = f.types.iter().map(|decl| (decl.name.clone(), decl.def.clone())).collect();
+	let consts = f.consts.iter().map(|decl| (decl.name.clone(), decl.typ.clone())).collect();
+	// Constants which cannot be evaluated are reported by the normalization
+	let mut values = HashMap::new();
+	for decl in &f.consts {
+		if let Ok(c) = eval(&decl.value, &values) {
+			values.insert(decl.name.clone(), c);
+		}
+	}
+	Ok(Globals{sigs, types, consts, values})
+}
+
+/// Returns the type of a well-typed expression in the body of node `n`.
+pub fn type_of_expr(e: &ast::Expr, n: &ast::Node, globals: &Globals) -> Result<Type, CompileError> {
+	Context::new(n, globals).type_of(e)
+}
+
+/// Checks that nodes are well-typed. Each constant and equation is checked even if the previous
+/// ones have errors.
+pub fn check(f: &ast::File) -> Result<(), CompileError> {
+	let globals = globals(f)?;
+	let mut errors = Vec::new();
+	for decl in &f.consts {
+		let ctx = Context{node: None, globals: &globals};
+		let checked = ctx.type_of(&decl.value).and_then(|t| {
+			ctx.expect(&decl.typ, &t, "the value of the constant", &decl.value.span)
+		});
+		errors.extend(checked.err());
+	}
+
+	for n in &f.nodes {
+		let ctx = Context::new(n, &globals);
+		let vars: Vec<&String> = n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter()).map(|(name, _)| name).collect();
+		for (i, name) in vars.iter().enumerate() {
+			let is_variant = f.types.iter().any(|decl| match &decl.def {
+				TypeDef::Enum(variants) => variants.contains(name),
+				_ => false,
+			});
+			let msg = if vars[..i].contains(name) {
+				format!("variable `{}` is declared twice", name)
+			} else if is_variant {
+				format!("variable `{}` has the name of an enumeration value", name)
+			} else if globals.consts.contains_key(*name) {
+				format!("variable `{}` has the name of a constant", name)
+			} else {
+				continue;
+			};
+			errors.push(CompileError::Definition(Diagnostic::new(&n.span, msg)));
+		}
+		for eq in &n.body {
+			errors.extend(ctx.check_equation(eq).err());
+		}
+	}
+	collect(errors)
+}
 
 pub fn type_of_const(c: &Const) -> Type {
 	match c {
@@ -9,49 +430,37 @@ pub fn type_of_const(c: &Const) -> Type {
 		Const::Int(_) => Type::Int,
 		Const::Float(_) => Type::Float,
 		Const::String(_) => Type::String,
+		Const::Array(consts) => Type::Array(Box::new(type_of_const(&consts[0])), consts.len()),
+		Const::Record(name, _) => Type::Record(name.clone()),
+		Const::Enum(name, _) => Type::Enum(name.clone()),
 	}
 }
 
-fn type_of_atom(a: &Atom) -> Type {
-	match a {
-		Atom::Const(c) => type_of_const(c),
-		Atom::Ident(_) => unreachable!(), // TODO
+/// Formats a constant used in an error message.
+fn format_const(c: &Const) -> String {
+	match c {
+		Const::Bool(b) => b.to_string(),
+		Const::Enum(_, variant) => variant.clone(),
+		_ => format!("{:?}", c),
 	}
 }
 
-fn type_of_bexpr(e: &Bexpr) -> Type {
-	match e {
-		Bexpr::Unop(op, _) => match op {
-			Unop::Minus => Type::Int,
-			Unop::MinusDot => Type::Float,
-			Unop::Not => Type::Bool,
-		},
-		Bexpr::Binop(op, _) => match op {
-			Binop::Plus | Binop::Minus | Binop::Mult | Binop::Div => Type::Int,
-			Binop::PlusDot | Binop::MinusDot | Binop::MultDot | Binop::DivDot => Type::Float,
-			Binop::Lt | Binop::Gt | Binop::Leq | Binop::Geq | Binop::Eq | Binop::And | Binop::Or => Type::Bool,
-		},
-		Bexpr::If(iff) => {
-			let (_, body, _): &(Bexpr, Bexpr, Bexpr) = &*iff;
-			type_of_bexpr(body)
-		},
-		Bexpr::Tuple(items) => Type::Tuple(items.iter().map(type_of_bexpr).collect()),
-		Bexpr::Atom(atom) => type_of_atom(atom),
+fn type_from_vec(v: Vec<Type>) -> Type {
+	if v.is_empty() {
+		Type::Unit
+	} else if v.len() == 1 {
+		v.into_iter().next().unwrap()
+	} else {
+		Type::Tuple(v)
 	}
 }
 
-pub fn type_of(e: &Expr) -> Type {
-	match e {
-		Expr::Call{name: _, args: _} => unreachable!(), // TODO
-		Expr::Fby(init, _) => {
-			if init.is_empty() {
-				Type::Unit
-			} else if init.len() == 1 {
-				type_of_atom(&init[0])
-			} else {
-				Type::Tuple(init.iter().map(type_of_atom).collect())
-			}
-		},
-		Expr::Bexpr(bexp) => type_of_bexpr(bexp),
-	}
+fn type_of_var(n: &Node, name: &str) -> Type {
+	let mut vars = n.args_in.iter().chain(n.args_out.iter()).chain(n.locals.iter());
+	vars.find(|(var, _)| var == name).map(|(_, t)| t.clone()).unwrap()
+}
+
+/// Returns the type of the variables defined by an equation in the body of node `n`.
+pub fn type_of_names(n: &Node, names: &[String]) -> Type {
+	type_from_vec(names.iter().map(|name| type_of_var(n, name)).collect())
 }
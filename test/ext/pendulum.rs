@@ -0,0 +1,23 @@
+// Implementation of the external functions and nodes of pendulum.mls
+
+/// Simulated mouse, which moves along a diagonal.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default)]
+struct MemGet_mouse {
+	x: i32,
+	y: i32,
+}
+
+fn get_mouse(mem: &mut MemGet_mouse, _u: ()) -> (i32, i32) {
+	mem.x += 1;
+	mem.y += 1;
+	return (mem.x, mem.y);
+}
+
+fn draw_line(x0: i32, y0: i32, x1: i32, y1: i32) -> () {
+	println!("line ({}, {}) -> ({}, {})", x0, y0, x1, y1);
+}
+
+fn draw_circle(x: i32, y: i32, r: i32) -> () {
+	println!("circle ({}, {}) r={}", x, y, r);
+}